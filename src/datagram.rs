@@ -0,0 +1,148 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Datagram-framed sessions for `udp://` and `quic://` endpoints. Each message
+//! is sent as a single UDP datagram, so the datagram boundaries are used as a
+//! message framing. QUIC stream multiplexing and encryption are not supported
+//! (yet), so `quic://` endpoints are dialed as plain datagram sessions.
+
+use std::any::Any;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+use internet2::addr::{InetSocketAddr, InetSocketAddrExt, Transport};
+use internet2::{transport, RoutedFrame, SendRecvMessage};
+
+/// Maximum size of a message which can be put into a single UDP datagram
+pub const MAX_DATAGRAM_SIZE: usize = 0xFFFF - 8 - 40;
+
+/// Session sending and receiving each message as a separate UDP datagram
+#[derive(Debug)]
+pub struct DatagramSession {
+    socket: UdpSocket,
+}
+
+impl DatagramSession {
+    /// Dials remote `udp://` or `quic://` endpoint, binding the local socket
+    /// to an OS-assigned port.
+    pub fn connect(remote: InetSocketAddrExt) -> Result<Self, transport::Error> {
+        let InetSocketAddrExt(proto, addr) = remote;
+        if proto != Transport::Udp && proto != Transport::Quic {
+            return Err(transport::Error::SocketIo(io::ErrorKind::InvalidInput));
+        }
+        let remote = match addr {
+            InetSocketAddr::IPv4(socket) => SocketAddr::V4(socket),
+            InetSocketAddr::IPv6(socket) => SocketAddr::V6(socket),
+            _ => return Err(transport::Error::TorNotSupportedYet),
+        };
+        let local = match remote {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        debug!("Creating datagram session from {} to {}", local, remote);
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(remote)?;
+        Ok(Self { socket })
+    }
+
+    /// Constructs session from UDP socket which must be already bound and
+    /// connected to the remote peer.
+    pub fn with_socket(socket: UdpSocket) -> Self { Self { socket } }
+
+    /// Returns the socket address the session is bound to
+    pub fn local_addr(&self) -> Result<SocketAddr, transport::Error> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Returns the socket address of the remote peer
+    pub fn remote_addr(&self) -> Result<SocketAddr, transport::Error> {
+        Ok(self.socket.peer_addr()?)
+    }
+}
+
+impl SendRecvMessage for DatagramSession {
+    fn recv_raw_message(&mut self) -> Result<Vec<u8>, transport::Error> {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let len = self.socket.recv(&mut buf)?;
+        buf.truncate(len);
+        trace!("Received datagram of {} bytes", len);
+        Ok(buf)
+    }
+
+    fn send_raw_message(&mut self, raw: &[u8]) -> Result<usize, transport::Error> {
+        if raw.len() > MAX_DATAGRAM_SIZE {
+            return Err(transport::Error::OversizedFrame(raw.len()));
+        }
+        trace!("Sending datagram of {} bytes", raw.len());
+        Ok(self.socket.send(raw)?)
+    }
+
+    /// Datagram sessions do not support message routing, so the function
+    /// always fails with [`io::ErrorKind::Unsupported`] socket error.
+    fn recv_routed_message(&mut self) -> Result<RoutedFrame, transport::Error> {
+        Err(transport::Error::SocketIo(io::ErrorKind::Unsupported))
+    }
+
+    /// Datagram sessions do not support message routing, so the function
+    /// always fails with [`io::ErrorKind::Unsupported`] socket error.
+    fn send_routed_message(
+        &mut self,
+        _source: &[u8],
+        _route: &[u8],
+        _dest: &[u8],
+        _raw: &[u8],
+    ) -> Result<usize, transport::Error> {
+        Err(transport::Error::SocketIo(io::ErrorKind::Unsupported))
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> { self }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+
+    use super::*;
+
+    #[test]
+    fn loopback_roundtrip() {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut client =
+            DatagramSession::connect(InetSocketAddrExt::udp(IpAddr::V4(Ipv4Addr::LOCALHOST), port))
+                .unwrap();
+
+        client.send_raw_message(b"ping").unwrap();
+        let mut buf = [0u8; 16];
+        let (len, client_addr) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+
+        server.connect(client_addr).unwrap();
+        let mut server = DatagramSession::with_socket(server);
+        server.send_raw_message(b"pong").unwrap();
+        assert_eq!(client.recv_raw_message().unwrap(), b"pong");
+    }
+
+    #[test]
+    fn routing_unsupported() {
+        let mut session = DatagramSession::with_socket(UdpSocket::bind("127.0.0.1:0").unwrap());
+        assert_eq!(
+            session.send_routed_message(b"src", b"route", b"dst", b"msg").unwrap_err(),
+            transport::Error::SocketIo(io::ErrorKind::Unsupported)
+        );
+        assert_eq!(
+            session.recv_routed_message().unwrap_err(),
+            transport::Error::SocketIo(io::ErrorKind::Unsupported)
+        );
+    }
+}
@@ -19,12 +19,39 @@ use amplify::hex::{self, ToHex};
 use internet2::addr::ServiceAddr;
 use internet2::{zeromq, ZmqSocketType};
 
+use crate::rpc::SocketOptions;
+
+/// Highest version of the ESB frame format supported by this library. Buses
+/// with [`BusConfig::versioned`] flag set prefix each message with the version
+/// byte.
+///
+/// Before sending the first message to a peer (or to the router on the way to
+/// it), the controllers announce the highest version they use with a control
+/// frame of version 0 followed by the announced version byte, and the peer
+/// announces its version back. Both sides then use the highest version
+/// supported by both of them; until the announcement of the peer is received,
+/// version 1 is used, which is supported by all of the peers. Frames of the
+/// versions unsupported by the receiver are rejected with
+/// [`Error::IncompatibleProtocolVersion`](super::Error::IncompatibleProtocolVersion),
+/// and its version is announced to the sender once again.
+///
+/// Version 1 frames contain just the message after the version byte. Version 2
+/// frames add correlation of the replies with the requests: the version byte
+/// is followed by the frame kind (0 for plain messages, 1 for requests and 2
+/// for replies) and, for requests and replies, by the request id encoded as a
+/// 64-bit big-endian integer.
+pub const ESB_FRAME_VERSION: u8 = 2;
+
 /// Marker traits for service bus identifiers
 pub trait BusId: Copy + Eq + Hash + Debug + Display {
     /// Service address type used by this bus
     type Address: ServiceAddress;
 }
 
+/// Configuration of the service bus. New configuration parameters may be added
+/// in the future, so the configuration must be constructed with one of the
+/// `with_*` constructors, adjusting the fields afterwards.
+#[non_exhaustive]
 pub struct BusConfig<A>
 where
     A: ServiceAddress,
@@ -35,7 +62,19 @@ where
     /// Indicates whether the messages must be queued, or the send function
     /// must fail immediately if the remote point is not available
     pub queued: bool,
+    /// Indicates that the bus carries control messages, which must be
+    /// processed by the controller before messages from the non-priority
+    /// buses received at the same time
+    pub priority: bool,
     pub topic: Option<String>,
+    /// Indicates that the messages on the bus are prefixed with the ESB frame
+    /// version byte (see [`ESB_FRAME_VERSION`]). All peers on the bus must
+    /// use the same setting, since unversioned messages can't be told apart
+    /// from the versioned ones.
+    pub versioned: bool,
+    /// ZMQ socket options (high-water marks, linger, CURVE authentication)
+    /// applied to the bus socket
+    pub socket_options: SocketOptions,
 }
 
 impl<A> BusConfig<A>
@@ -48,7 +87,10 @@ where
             carrier: zeromq::Carrier::Locator(addr),
             router,
             queued: false,
+            priority: false,
             topic: None,
+            versioned: false,
+            socket_options: none!(),
         }
     }
 
@@ -63,7 +105,10 @@ where
             router: None,
             queued: true, /* this doesn't work, but prevents from setting flag on PUB/SUB socket
                            * which will always fail */
+            priority: false,
             topic,
+            versioned: false,
+            socket_options: none!(),
         }
     }
 
@@ -73,7 +118,10 @@ where
             carrier: zeromq::Carrier::Socket(socket),
             router,
             queued: false,
+            priority: false,
             topic: None,
+            versioned: false,
+            socket_options: none!(),
         }
     }
 }
@@ -84,8 +132,121 @@ pub trait ServiceAddress:
 {
 }
 
+/// Implements [`ServiceAddress`] for a newtype wrapping `Vec<u8>` byte
+/// identity, including conversions from and into the byte representation.
+///
+/// The type must also implement `Clone`, `Eq`, `Hash`, `Debug` and `Display`,
+/// which are usually derived.
+#[macro_export]
+macro_rules! impl_service_address {
+    ($ty:ty) => {
+        impl From<Vec<u8>> for $ty {
+            fn from(vec: Vec<u8>) -> Self { Self(vec) }
+        }
+
+        impl From<$ty> for Vec<u8> {
+            fn from(addr: $ty) -> Self { addr.0 }
+        }
+
+        impl $crate::esb::ServiceAddress for $ty {}
+    };
+}
+
 pub type ClientId = u64;
 
+/// Reusable [`ServiceAddress`] for the nodes which do not need a custom one,
+/// with wire-stable byte encodings of the variants.
+///
+/// Byte strings which do not match any of the known encodings are decoded as
+/// [`StandardServiceAddress::Unknown`], keeping the forward compatibility with
+/// the addresses introduced by the newer versions. Such byte strings should
+/// not be constructed manually: unknown variant encoded as a known address is
+/// decoded into that known address.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum StandardServiceAddress {
+    /// Message router
+    Router,
+
+    /// Main node daemon
+    Daemon,
+
+    /// Wallet service
+    Wallet,
+
+    /// Service managing connections to the remote peers
+    Peer,
+
+    /// Signing service
+    Signer,
+
+    /// Client connected to the node
+    Client(ClientId),
+
+    /// Address of unknown format
+    Unknown(Vec<u8>),
+}
+
+impl StandardServiceAddress {
+    const TAG_ROUTER: u8 = 0x01;
+    const TAG_DAEMON: u8 = 0x02;
+    const TAG_WALLET: u8 = 0x03;
+    const TAG_PEER: u8 = 0x04;
+    const TAG_SIGNER: u8 = 0x05;
+    const TAG_CLIENT: u8 = 0x10;
+}
+
+impl Display for StandardServiceAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            StandardServiceAddress::Router => f.write_str("router"),
+            StandardServiceAddress::Daemon => f.write_str("daemon"),
+            StandardServiceAddress::Wallet => f.write_str("wallet"),
+            StandardServiceAddress::Peer => f.write_str("peer"),
+            StandardServiceAddress::Signer => f.write_str("signer"),
+            StandardServiceAddress::Client(id) => write!(f, "client#{}", id),
+            StandardServiceAddress::Unknown(data) => write!(f, "unknown({})", data.to_hex()),
+        }
+    }
+}
+
+impl From<Vec<u8>> for StandardServiceAddress {
+    fn from(vec: Vec<u8>) -> Self {
+        match vec[..] {
+            [Self::TAG_ROUTER] => StandardServiceAddress::Router,
+            [Self::TAG_DAEMON] => StandardServiceAddress::Daemon,
+            [Self::TAG_WALLET] => StandardServiceAddress::Wallet,
+            [Self::TAG_PEER] => StandardServiceAddress::Peer,
+            [Self::TAG_SIGNER] => StandardServiceAddress::Signer,
+            [Self::TAG_CLIENT, ref id @ ..] if id.len() == 8 => {
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(id);
+                StandardServiceAddress::Client(ClientId::from_be_bytes(buf))
+            }
+            _ => StandardServiceAddress::Unknown(vec),
+        }
+    }
+}
+
+impl From<StandardServiceAddress> for Vec<u8> {
+    fn from(addr: StandardServiceAddress) -> Self {
+        match addr {
+            StandardServiceAddress::Router => vec![StandardServiceAddress::TAG_ROUTER],
+            StandardServiceAddress::Daemon => vec![StandardServiceAddress::TAG_DAEMON],
+            StandardServiceAddress::Wallet => vec![StandardServiceAddress::TAG_WALLET],
+            StandardServiceAddress::Peer => vec![StandardServiceAddress::TAG_PEER],
+            StandardServiceAddress::Signer => vec![StandardServiceAddress::TAG_SIGNER],
+            StandardServiceAddress::Client(id) => {
+                let mut vec = vec![StandardServiceAddress::TAG_CLIENT];
+                vec.extend(id.to_be_bytes());
+                vec
+            }
+            StandardServiceAddress::Unknown(data) => data,
+        }
+    }
+}
+
+impl ServiceAddress for StandardServiceAddress {}
+
 #[derive(Wrapper, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, From, Default)]
 #[derive(StrictEncode, StrictDecode)]
 pub struct ServiceName([u8; 32]);
@@ -115,3 +276,56 @@ impl FromStr for ServiceName {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
+    #[display(Debug)]
+    struct NodeAddr(Vec<u8>);
+
+    impl_service_address!(NodeAddr);
+
+    fn roundtrip<A: ServiceAddress>(addr: A) {
+        let bytes: Vec<u8> = addr.clone().into();
+        assert_eq!(A::from(bytes), addr);
+    }
+
+    #[test]
+    fn service_address_macro_roundtrip() {
+        roundtrip(NodeAddr(vec![]));
+        roundtrip(NodeAddr(b"wallet".to_vec()));
+        assert_eq!(Vec::<u8>::from(NodeAddr(b"peer".to_vec())), b"peer".to_vec());
+        assert_eq!(NodeAddr::from(b"peer".to_vec()), NodeAddr(b"peer".to_vec()));
+    }
+
+    #[test]
+    fn standard_service_address_roundtrip() {
+        let addrs = [
+            (StandardServiceAddress::Router, vec![0x01]),
+            (StandardServiceAddress::Daemon, vec![0x02]),
+            (StandardServiceAddress::Wallet, vec![0x03]),
+            (StandardServiceAddress::Peer, vec![0x04]),
+            (StandardServiceAddress::Signer, vec![0x05]),
+            (StandardServiceAddress::Client(0x0102030405060708), vec![
+                0x10, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
+            ]),
+            (StandardServiceAddress::Unknown(b"ext-service".to_vec()), b"ext-service".to_vec()),
+        ];
+        for (addr, bytes) in addrs {
+            // Byte encodings are part of the wire protocol and must not change
+            assert_eq!(Vec::<u8>::from(addr.clone()), bytes);
+            roundtrip(addr);
+        }
+
+        // Malformed client ids and unassigned tags are kept as unknown blobs
+        for blob in [vec![], vec![0x00], vec![0x10, 0x01], vec![0x02, 0x02]] {
+            assert_eq!(
+                StandardServiceAddress::from(blob.clone()),
+                StandardServiceAddress::Unknown(blob)
+            );
+        }
+    }
+}
+
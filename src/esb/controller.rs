@@ -11,21 +11,35 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
-use std::collections::HashMap;
-use std::io::Cursor;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Cursor};
+use std::sync::Arc;
+use std::time::Duration;
 
+use internet2::addr::ServiceAddr;
 use internet2::session::LocalSession;
-use internet2::{zeromq, SendRecvMessage, Unmarshall, Unmarshaller, ZmqSocketType};
+use internet2::{
+    presentation, transport, zeromq, SendRecvMessage, Unmarshall, Unmarshaller, ZmqSocketType,
+};
 
-use super::{BusId, Error, ServiceAddress};
+use super::dedup::DedupFilter;
+use super::watchdog::Watchdog;
+use super::{BusId, Error, HandlerStats, ServiceAddress, StuckHandlerCallback, ESB_FRAME_VERSION};
 use crate::esb::BusConfig;
 #[cfg(feature = "node")]
-use crate::node::TryService;
-use crate::rpc::Request;
+use crate::node::{TryService, TryServiceStep};
+use crate::rpc::{Request, SocketOptions};
+use crate::util::{Clock, SystemClock};
 use crate::ZMQ_CONTEXT;
 
 /// Trait for types handling specific set of ESB RPC API requests structured as
 /// a single type implementing [`Request`].
+///
+/// Each [`Controller`] has a single handler, which receives messages from all
+/// of the controller service buses uniformly, including the buses added after
+/// the controller construction. Handlers which need to distinguish the buses
+/// use the `bus_id` argument of [`Handler::handle`]; gateway-like handlers may
+/// ignore it, so adding a new bus does not require changes to the handler.
 pub trait Handler<B>
 where
     Self: Sized,
@@ -39,6 +53,10 @@ where
 
     fn on_ready(&mut self, _endpoints: &mut EndpointList<B>) -> Result<(), Self::Error> { Ok(()) }
 
+    /// Handles `request` from `source` received via `bus_id` service bus,
+    /// which is the only discriminator of the bus the message came from. If
+    /// the request was sent with [`Controller::request_reply`], the first
+    /// message sent back to `source` during the call is taken as its reply.
     fn handle(
         &mut self,
         endpoints: &mut EndpointList<B>,
@@ -52,6 +70,48 @@ where
         endpoints: &mut EndpointList<B>,
         error: Error<B::Address>,
     ) -> Result<(), Self::Error>;
+
+    /// Called when a message received from `source` via `bus_id` can't be
+    /// decoded into [`Handler::Request`], providing the `raw` message data,
+    /// such that the service may log or quarantine it. Defaults to
+    /// [`Handler::handle_err`].
+    fn on_decode_error(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        _bus_id: B,
+        _source: B::Address,
+        _raw: &[u8],
+        error: Error<B::Address>,
+    ) -> Result<(), Self::Error> {
+        self.handle_err(endpoints, error)
+    }
+}
+
+/// Check performed by [`Controller`] before passing the request to
+/// [`Handler::handle`], allowing cross-cutting validation (like authorization
+/// of the request sources) to be implemented once for all requests.
+/// Implemented for closures taking the request source and a reference to the
+/// request.
+///
+/// ESB has no generic failure reply, so the error returned by a failed check
+/// is reported to [`Handler::handle_err`], which may notify the source.
+pub trait RequestFilter<A, R>
+where
+    A: ServiceAddress,
+    R: Request,
+{
+    /// Checks `request` received from `source`; requests failing the check
+    /// are not passed to the handler.
+    fn check(&self, source: &A, request: &R) -> Result<(), Error<A>>;
+}
+
+impl<A, R, F> RequestFilter<A, R> for F
+where
+    A: ServiceAddress,
+    R: Request,
+    F: Fn(&A, &R) -> Result<(), Error<A>>,
+{
+    fn check(&self, source: &A, request: &R) -> Result<(), Error<A>> { self(source, request) }
 }
 
 struct Endpoint<A>
@@ -60,55 +120,252 @@ where
 {
     pub(self) session: LocalSession,
     pub(self) router: Option<A>,
+    pub(self) priority: bool,
+    pub(self) versioned: bool,
+    /// Highest frame version used on the bus, which is announced to the peers
+    pub(self) max_version: u8,
+    /// Frame versions agreed with the next hops, i.e. the highest versions
+    /// supported by both sides
+    pub(self) peer_versions: HashMap<A, u8>,
+    /// Next hops which were sent the announcement of our highest version
+    pub(self) announced: HashSet<A>,
+    /// Parameters of the session connected by the controller itself, used to
+    /// reopen it with a different identity; `None` for the sessions provided
+    /// by the user
+    pub(self) locator: Option<BusLocator>,
+}
+
+/// Parameters of the service bus session connected by the controller
+struct BusLocator {
+    pub(self) api_type: ZmqSocketType,
+    pub(self) addr: ServiceAddr,
+    pub(self) socket_options: SocketOptions,
+    pub(self) queued: bool,
+    pub(self) topic: Option<String>,
+}
+
+impl BusLocator {
+    /// Creates new session connected with `identity` and configured according
+    /// to the bus parameters
+    pub(self) fn open(&self, identity: &[u8]) -> Result<LocalSession, transport::Error> {
+        let session = self.socket_options.connect(self.api_type, &self.addr, Some(identity))?;
+        configure_session(&session, self.api_type, self.queued, self.topic.as_deref())?;
+        Ok(session)
+    }
+}
+
+/// Sets up bus-specific options of the session socket
+fn configure_session(
+    session: &LocalSession,
+    api_type: ZmqSocketType,
+    queued: bool,
+    topic: Option<&str>,
+) -> Result<(), transport::Error> {
+    if !queued {
+        session.as_socket().set_router_mandatory(true)?;
+    }
+    if api_type == ZmqSocketType::Sub {
+        session.as_socket().set_subscribe(topic.unwrap_or_default().as_bytes())?;
+    }
+    Ok(())
 }
 
 impl<A> Endpoint<A>
 where
     A: ServiceAddress,
 {
-    pub(self) fn send_to<R>(&mut self, source: A, dest: A, request: R) -> Result<(), Error<A>>
-    where
-        R: Request,
-    {
-        let data = request.serialize();
-        let router = match self.router {
-            None => {
-                trace!("Sending {} from {} to {} directly", request, source, dest,);
-                dest.clone()
-            }
-            Some(ref router) if &source == router => {
-                trace!("Routing {} from {} to {}", request, source, dest,);
-                dest.clone()
-            }
-            Some(ref router) => {
-                trace!("Sending {} from {} to {} via router {}", request, source, dest, router,);
-                router.clone()
-            }
-        };
+    /// Sends already serialized message, returning the size of the message
+    /// payload (not including the frame header and transport framing)
+    pub(self) fn send_data(
+        &mut self,
+        source: A,
+        dest: A,
+        correlation: Correlation,
+        data: Vec<u8>,
+    ) -> Result<usize, Error<A>> {
+        let len = data.len();
+        let router = self.next_hop(&source, &dest);
+        self.announce(&source, &router)?;
+        let data = self.encode_frame(&router, correlation, data);
+        trace!("Sending {} bytes from {} to {} via {}", data.len(), source, dest, router);
         let src = source.clone();
         let dst = dest.clone();
         self.session
             .send_routed_message(&source.into(), &router.into(), &dest.into(), &data)
-            .map_err(|err| Error::Send(src, dst, err))?;
+            .map(|_| len)
+            .map_err(|err| Error::Send(src, dst, err))
+    }
+
+    /// Sends the announcement of the highest frame version used by us to the
+    /// next `hop`, unless it was already sent or the bus is not versioned.
+    /// Announcements are control frames of version 0 containing just the
+    /// announced version, which are not delivered to the handler.
+    pub(self) fn announce(&mut self, source: &A, hop: &A) -> Result<(), Error<A>> {
+        if !self.versioned || self.announced.contains(hop) {
+            return Ok(());
+        }
+        trace!("Announcing ESB frame version {} to {}", self.max_version, hop);
+        let route: Vec<u8> = hop.clone().into();
+        self.session
+            .send_routed_message(&source.clone().into(), &route, &route, &[0, self.max_version])
+            .map_err(|err| Error::Send(source.clone(), hop.clone(), err))?;
+        self.announced.insert(hop.clone());
         Ok(())
     }
 
-    #[inline]
+    /// Prefixes serialized message with the frame header of the version
+    /// agreed with the next `hop`, if the bus is versioned. Until the hop
+    /// announces its version, version 1 is used, since it is supported by all
+    /// of the peers. Frames of version 1 can't carry the message
+    /// `correlation`, which is dropped.
+    pub(self) fn encode_frame(&self, hop: &A, correlation: Correlation, data: Vec<u8>) -> Vec<u8> {
+        if !self.versioned {
+            return data;
+        }
+        let version = self.peer_versions.get(hop).copied().unwrap_or(1);
+        let mut frame = Vec::with_capacity(data.len() + 10);
+        frame.push(version);
+        if version >= 2 {
+            encode_correlation(correlation, &mut frame);
+        } else if correlation != Correlation::None {
+            debug!("Dropping {:?} of the message to {} using frame version 1", correlation, hop);
+        }
+        frame.extend(data);
+        frame
+    }
+
+    /// Strips frame header from the message received from `source` via the
+    /// previous `hop`, if the bus is versioned, returning the message
+    /// correlation, which is [`Correlation::None`] for the unversioned buses
+    /// and the frames of version 1.
+    ///
+    /// Version announcements of the hops are consumed by the function, which
+    /// returns `None` for them: the version agreed with the hop is set to the
+    /// highest version supported by both sides, and our own version is
+    /// announced back. Frames of the versions above the one we use are
+    /// rejected with [`Error::IncompatibleProtocolVersion`], and our version
+    /// is announced to the hop anew, so it falls back to it.
+    pub(self) fn decode_frame<'msg>(
+        &mut self,
+        hop: &A,
+        source: &A,
+        dest: &A,
+        msg: &'msg [u8],
+    ) -> Result<Option<(Correlation, &'msg [u8])>, Error<A>> {
+        if !self.versioned {
+            return Ok(Some((Correlation::None, msg)));
+        }
+        match msg {
+            [0, version] if *version > 0 => {
+                let version = (*version).min(self.max_version);
+                if self.peer_versions.insert(hop.clone(), version) != Some(version) {
+                    debug!("Using ESB frame version {} with {}", version, hop);
+                }
+                self.announce(dest, hop)?;
+                Ok(None)
+            }
+            [version, data @ ..] if *version > 0 && *version <= self.max_version => {
+                if *version == 1 {
+                    return Ok(Some((Correlation::None, data)));
+                }
+                decode_correlation(data)
+                    .map(Some)
+                    .ok_or(Error::Presentation(presentation::Error::InvalidValue))
+            }
+            [version, ..] if *version > self.max_version => {
+                self.announced.remove(hop);
+                self.announce(dest, hop)?;
+                Err(Error::IncompatibleProtocolVersion(source.clone(), *version))
+            }
+            _ => Err(Error::IncompatibleProtocolVersion(source.clone(), 0)),
+        }
+    }
+
+    /// Detects address of the next hop for the message: either the router or
+    /// the destination itself, if no router is used or if we are the router.
+    pub(self) fn next_hop(&self, source: &A, dest: &A) -> A {
+        match self.router {
+            Some(ref router) if source != router => router.clone(),
+            _ => dest.clone(),
+        }
+    }
+
+    /// Changes identity of the session. Sessions connected by the controller
+    /// are reopened with all of the bus socket options applied anew.
     pub(self) fn set_identity(&mut self, identity: A) -> Result<(), Error<A>> {
-        self.session.set_identity(&identity.into(), &ZMQ_CONTEXT).map_err(Error::from)
+        let identity: Vec<u8> = identity.into();
+        match self.locator {
+            Some(ref locator) => self.session = locator.open(&identity)?,
+            None => self.session.set_identity(&identity, &ZMQ_CONTEXT)?,
+        }
+        Ok(())
     }
 }
 
 #[derive(Default)]
-pub struct EndpointList<B>(pub(self) HashMap<B, Endpoint<B::Address>>)
+pub struct EndpointList<B>
 where
-    B: BusId;
+    B: BusId,
+{
+    pub(self) buses: HashMap<B, Endpoint<B::Address>>,
+    /// Identity of the controller owning the list; messages sent to it are
+    /// delivered locally without a socket round trip
+    pub(self) identity: Option<B::Address>,
+    /// Messages sent by the controller to itself: bus id, source and the
+    /// serialized request
+    pub(self) loopback: VecDeque<(B, B::Address, Vec<u8>)>,
+    /// Multipart messages sent by the controller to itself
+    pub(self) loopback_multipart: VecDeque<MultipartItem<B>>,
+    /// Messages which have failed to be sent and are retained for retrying
+    pub(self) pending: VecDeque<PendingSend<B>>,
+    /// Maximum number of the retained failed messages; zero disables retaining
+    pub(self) send_buffer: usize,
+    /// Source and id of the request which is being handled; the first message
+    /// sent back to the source while handling the request is its reply
+    pub(self) replying_to: Option<(B::Address, u64)>,
+}
+
+/// Message which has failed to be sent and is retained by [`EndpointList`] for
+/// retrying with [`EndpointList::retry_pending`]
+#[derive(Clone, Debug)]
+pub struct PendingSend<B>
+where
+    B: BusId,
+{
+    pub bus_id: B,
+    pub source: B::Address,
+    pub dest: B::Address,
+    /// Serialized message
+    pub data: Vec<u8>,
+    correlation: Correlation,
+}
 
 impl<B> EndpointList<B>
 where
     B: BusId,
 {
-    pub fn new() -> Self { Self(Default::default()) }
+    pub fn new() -> Self {
+        Self {
+            buses: none!(),
+            identity: None,
+            loopback: none!(),
+            loopback_multipart: none!(),
+            pending: none!(),
+            send_buffer: 0,
+            replying_to: None,
+        }
+    }
+
+    pub(self) fn with_identity(identity: B::Address) -> Self {
+        Self { identity: Some(identity), ..Self::new() }
+    }
+
+    /// Checks whether the service bus with the given id is configured, i.e.
+    /// messages can be sent through it without [`Error::UnknownBusId`].
+    pub fn can_send(&self, bus_id: B) -> bool { self.buses.contains_key(&bus_id) }
+
+    /// Returns iterator over the ids of all configured service buses
+    pub fn bus_ids(&self) -> impl Iterator<Item = B> + '_ { self.buses.keys().copied() }
 
     pub fn send_to<R>(
         &mut self,
@@ -120,9 +377,196 @@ where
     where
         R: Request,
     {
+        self.send_to_counted(bus_id, source, dest, request).map(|_| ())
+    }
+
+    /// Sends the request in the same way as [`EndpointList::send_to`], returning
+    /// the size of the encoded request payload in bytes. The size does not
+    /// depend on the bus configuration: neither the frame version byte of the
+    /// versioned buses nor the transport framing are counted.
+    pub fn send_to_counted<R>(
+        &mut self,
+        bus_id: B,
+        source: B::Address,
+        dest: B::Address,
+        request: R,
+    ) -> Result<usize, Error<B::Address>>
+    where
+        R: Request,
+    {
+        trace!("Sending {} from {} to {}", request, source, dest);
+        let correlation = self.correlation_for(&dest);
+        self.send_serialized(bus_id, source, dest, correlation, Self::encode_once(&request))
+    }
+
+    /// Sends request which was already serialized (with `serialize` method of
+    /// the request type), such that services broadcasting the same message to
+    /// many destinations may encode it only once. Returns the size of the
+    /// payload in bytes.
+    pub fn send_encoded(
+        &mut self,
+        bus_id: B,
+        source: B::Address,
+        dest: B::Address,
+        data: &[u8],
+    ) -> Result<usize, Error<B::Address>> {
+        let correlation = self.correlation_for(&dest);
+        self.send_serialized(bus_id, source, dest, correlation, data.to_vec())
+    }
+
+    /// Serializes `request` in the same way as it is done by the send
+    /// functions, such that the data can be inspected, cached or sent with
+    /// [`EndpointList::send_encoded`] multiple times.
+    pub fn encode_once<R>(request: &R) -> Vec<u8>
+    where
+        R: Request,
+    {
+        request.serialize()
+    }
+
+    /// Sends the same `request` to each of `dests`, serializing it only once.
+    /// Stops on the first failure; otherwise returns the number of the
+    /// destinations the request was sent to.
+    pub fn send_to_many<R>(
+        &mut self,
+        bus_id: B,
+        source: B::Address,
+        dests: impl IntoIterator<Item = B::Address>,
+        request: R,
+    ) -> Result<usize, Error<B::Address>>
+    where
+        R: Request,
+    {
+        let data = Self::encode_once(&request);
+        let mut count = 0usize;
+        for dest in dests {
+            trace!("Sending {} from {} to {}", request, source, dest);
+            self.send_encoded(bus_id, source.clone(), dest, &data)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Returns correlation of the message sent to `dest`: the message is the
+    /// reply to the request being handled, if it is the first message sent
+    /// back to the request source.
+    fn correlation_for(&mut self, dest: &B::Address) -> Correlation {
+        match self.replying_to.take() {
+            Some((ref source, id)) if source == dest => Correlation::Reply(id),
+            replying_to => {
+                self.replying_to = replying_to;
+                Correlation::None
+            }
+        }
+    }
+
+    fn send_serialized(
+        &mut self,
+        bus_id: B,
+        source: B::Address,
+        dest: B::Address,
+        correlation: Correlation,
+        data: Vec<u8>,
+    ) -> Result<usize, Error<B::Address>> {
         let session =
-            self.0.get_mut(&bus_id).ok_or_else(|| Error::UnknownBusId(bus_id.to_string()))?;
-        session.send_to(source, dest, request)
+            self.buses.get_mut(&bus_id).ok_or_else(|| Error::UnknownBusId(bus_id.to_string()))?;
+        if self.identity.as_ref() == Some(&dest) {
+            trace!("Delivering message from {} to self", source);
+            let len = data.len();
+            self.loopback.push_back((bus_id, source, data));
+            return Ok(len);
+        }
+        if self.send_buffer == 0 {
+            return session.send_data(source, dest, correlation, data);
+        }
+        match session.send_data(source.clone(), dest.clone(), correlation, data.clone()) {
+            Err(err @ Error::Send(..)) => {
+                self.retain_pending(PendingSend { bus_id, source, dest, data, correlation });
+                Err(err)
+            }
+            res => res,
+        }
+    }
+
+    /// Enables retaining up to `capacity` messages which have failed to be
+    /// sent, such that they can be retried with
+    /// [`EndpointList::retry_pending`] (for instance once the service bus is
+    /// re-added after a failure). The send functions still return the error.
+    /// Once the capacity is reached, the oldest messages are discarded. Zero
+    /// capacity (the default) disables retaining.
+    pub fn set_send_buffer(&mut self, capacity: usize) {
+        self.send_buffer = capacity;
+        while self.pending.len() > capacity {
+            self.pending.pop_front();
+        }
+    }
+
+    /// Returns iterator over the retained messages which have failed to be
+    /// sent, starting from the oldest one
+    pub fn pending_sends(&self) -> impl Iterator<Item = &PendingSend<B>> + '_ {
+        self.pending.iter()
+    }
+
+    /// Retries sending the retained messages in the order they were sent.
+    /// Messages which fail again are retained. Returns the number of the
+    /// messages which were sent successfully.
+    pub fn retry_pending(&mut self) -> usize {
+        let mut sent = 0usize;
+        for pending in std::mem::take(&mut self.pending) {
+            let res = match self.buses.get_mut(&pending.bus_id) {
+                Some(endpoint) => endpoint.send_data(
+                    pending.source.clone(),
+                    pending.dest.clone(),
+                    pending.correlation,
+                    pending.data.clone(),
+                ),
+                None => Err(Error::UnknownBusId(pending.bus_id.to_string())),
+            };
+            match res {
+                Ok(_) => sent += 1,
+                Err(err) => {
+                    debug!("Retry of message from {} failed: {}", pending.source, err);
+                    self.pending.push_back(pending);
+                }
+            }
+        }
+        sent
+    }
+
+    fn retain_pending(&mut self, pending: PendingSend<B>) {
+        if self.pending.len() >= self.send_buffer {
+            warn!("Send buffer is full, discarding the oldest failed message");
+            self.pending.pop_front();
+        }
+        debug!("Retaining failed message from {} to {} for retry", pending.source, pending.dest);
+        self.pending.push_back(pending);
+    }
+
+    /// Sends message consisting of multiple parts (for instance header and
+    /// body), which must be received with [`Controller::recv_multipart`].
+    ///
+    /// The parts are packed into a single ESB message, so the message is
+    /// routed, versioned, retained for retrying and delivered to self in the
+    /// same way as the requests sent with [`EndpointList::send_to`].
+    pub fn send_to_multipart(
+        &mut self,
+        bus_id: B,
+        source: B::Address,
+        dest: B::Address,
+        parts: &[&[u8]],
+    ) -> Result<(), Error<B::Address>> {
+        if !self.buses.contains_key(&bus_id) {
+            return Err(Error::UnknownBusId(bus_id.to_string()));
+        }
+        if self.identity.as_ref() == Some(&dest) {
+            trace!("Delivering multipart message from {} to self", source);
+            let parts = parts.iter().map(|part| part.to_vec()).collect();
+            self.loopback_multipart.push_back(MultipartItem { bus_id, source, dest, parts });
+            return Ok(());
+        }
+        trace!("Sending {} parts from {} to {}", parts.len(), source, dest);
+        let correlation = self.correlation_for(&dest);
+        self.send_serialized(bus_id, source, dest, correlation, encode_parts(parts)).map(|_| ())
     }
 
     pub fn set_identity(
@@ -130,13 +574,30 @@ where
         bus_id: B,
         identity: B::Address,
     ) -> Result<(), Error<B::Address>> {
-        self.0
+        self.buses
             .get_mut(&bus_id)
             .ok_or_else(|| Error::UnknownBusId(bus_id.to_string()))?
             .set_identity(identity)
     }
+
+    fn close(&mut self) {
+        for (bus_id, endpoint) in self.buses.drain() {
+            debug!("Closing ESB session for service bus {}", bus_id);
+            if let Err(err) = endpoint.session.as_socket().set_linger(0) {
+                warn!("Unable to reset linger for service bus {}: {}", bus_id, err);
+            }
+        }
+    }
 }
 
+/// Callback receiving messages which can't be routed to their destination,
+/// with the id of the service bus they were received from, their source,
+/// destination and raw data.
+pub type DeadLetterSink<B> =
+    Box<dyn FnMut(B, <B as BusId>::Address, <B as BusId>::Address, &[u8]) + Send>;
+
+/// Enterprise service bus controller, running a single [`Handler`] for the
+/// messages received from all of its service buses.
 #[derive(Getters)]
 pub struct Controller<B, R, H>
 where
@@ -146,8 +607,176 @@ where
     Error<B::Address>: From<H::Error>,
 {
     endpoints: EndpointList<B>,
-    unmarshaller: Unmarshaller<R>,
+    unmarshaller: Arc<Unmarshaller<R>>,
     handler: H,
+    #[getter(skip)]
+    dead_letter: Option<DeadLetterSink<B>>,
+    /// Durations of the handler calls
+    handler_stats: HandlerStats,
+    #[getter(skip)]
+    watchdog: Option<Watchdog>,
+    /// Id of the next request sent with [`Controller::request_reply`]
+    #[getter(skip)]
+    next_request_id: u64,
+    #[getter(skip)]
+    frame_version: u8,
+    #[getter(skip)]
+    dedup: Option<DedupFilter>,
+    #[getter(skip)]
+    clock: Arc<dyn Clock + Send + Sync>,
+    #[getter(skip)]
+    filters: Vec<Box<dyn RequestFilter<B::Address, R> + Send>>,
+}
+
+/// Configuration of the [`Controller`] provided on its construction. The
+/// structure is non-exhaustive, so it should be constructed with
+/// [`ControllerConfig::default`] and then adjusted.
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct ControllerConfig {
+    /// Number of the recently received messages remembered by the filter
+    /// dropping repeated messages, which in mesh topologies may arrive via
+    /// multiple service buses. Messages are considered repeated if a message
+    /// with the same source, destination and content is remembered by the
+    /// filter; thus protocols which legitimately repeat identical messages
+    /// must not use the filter. Zero disables the filter (the default).
+    pub dedup_window: usize,
+
+    /// Time for which the filter of the repeated messages remembers each of
+    /// the messages. `None` (the default) remembers messages until they are
+    /// pushed out of the window by the newer ones.
+    pub dedup_ttl: Option<Duration>,
+
+    /// Source of the current time for the time-based logic of the controller:
+    /// measuring of the handler calls and the watchdog timeout, the
+    /// [`Controller::request_reply`] timeout and the filter of the repeated
+    /// messages. Defaults to the system clock.
+    pub clock: Arc<dyn Clock + Send + Sync>,
+
+    /// Highest ESB frame version used on the versioned buses, which is
+    /// announced to the peers; defaults to [`ESB_FRAME_VERSION`]. Values
+    /// outside of `1..=ESB_FRAME_VERSION` are clamped to that range.
+    pub frame_version: u8,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        ControllerConfig {
+            dedup_window: 0,
+            dedup_ttl: None,
+            clock: Arc::new(SystemClock),
+            frame_version: ESB_FRAME_VERSION,
+        }
+    }
+}
+
+/// Correlation of the message with a request, carried by the frames of
+/// version 2 and above (see [`ESB_FRAME_VERSION`])
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Correlation {
+    /// Plain message
+    None,
+    /// Request awaiting the reply with the same id
+    Request(u64),
+    /// Reply to the request with the given id
+    Reply(u64),
+}
+
+/// Appends frame kind and request id (if any) representing `correlation` to
+/// the version 2 frame header
+fn encode_correlation(correlation: Correlation, frame: &mut Vec<u8>) {
+    match correlation {
+        Correlation::None => frame.push(0),
+        Correlation::Request(id) => {
+            frame.push(1);
+            frame.extend_from_slice(&id.to_be_bytes());
+        }
+        Correlation::Reply(id) => {
+            frame.push(2);
+            frame.extend_from_slice(&id.to_be_bytes());
+        }
+    }
+}
+
+/// Reads correlation encoded with [`encode_correlation`], returning it
+/// together with the rest of the frame; returns `None` if the frame header is
+/// malformed.
+fn decode_correlation(data: &[u8]) -> Option<(Correlation, &[u8])> {
+    let (&kind, data) = data.split_first()?;
+    if kind == 0 {
+        return Some((Correlation::None, data));
+    }
+    if data.len() < 8 {
+        return None;
+    }
+    let (id, data) = data.split_at(8);
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(id);
+    let id = u64::from_be_bytes(buf);
+    match kind {
+        1 => Some((Correlation::Request(id), data)),
+        2 => Some((Correlation::Reply(id), data)),
+        _ => None,
+    }
+}
+
+/// Packs parts of a multipart message into a single ESB message: number of the
+/// parts followed by each of the parts prefixed with its length, with both
+/// encoded as 32-bit big-endian integers.
+fn encode_parts(parts: &[&[u8]]) -> Vec<u8> {
+    let len = parts.iter().map(|part| part.len() + 4).sum::<usize>() + 4;
+    let mut data = Vec::with_capacity(len);
+    data.extend_from_slice(&(parts.len() as u32).to_be_bytes());
+    for part in parts {
+        data.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        data.extend_from_slice(part);
+    }
+    data
+}
+
+/// Unpacks parts of a multipart message packed with [`encode_parts`];
+/// returns `None` if the message is malformed.
+fn decode_parts(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    fn read_u32(data: &mut &[u8]) -> Option<usize> {
+        if data.len() < 4 {
+            return None;
+        }
+        let (int, rest) = data.split_at(4);
+        *data = rest;
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(int);
+        Some(u32::from_be_bytes(buf) as usize)
+    }
+
+    let mut data = data;
+    let count = read_u32(&mut data)?;
+    // Each part takes at least 4 bytes, which bounds the allocation
+    let mut parts = Vec::with_capacity(count.min(data.len() / 4));
+    for _ in 0..count {
+        let len = read_u32(&mut data)?;
+        if data.len() < len {
+            return None;
+        }
+        let (part, rest) = data.split_at(len);
+        parts.push(part.to_vec());
+        data = rest;
+    }
+    if !data.is_empty() {
+        return None;
+    }
+    Some(parts)
+}
+
+/// Multipart message received by [`Controller::recv_multipart`]
+#[derive(Clone, Debug)]
+pub struct MultipartItem<B>
+where
+    B: BusId,
+{
+    pub bus_id: B,
+    pub source: B::Address,
+    pub dest: B::Address,
+    pub parts: Vec<Vec<u8>>,
 }
 
 #[derive(Debug)]
@@ -172,56 +801,159 @@ where
         service_bus: HashMap<B, BusConfig<B::Address>>,
         handler: H,
     ) -> Result<Self, Error<B::Address>> {
-        let endpoints = EndpointList::new();
-        let unmarshaller = R::create_unmarshaller();
-        let mut me = Self { endpoints, unmarshaller, handler };
+        Self::with_config(service_bus, handler, ControllerConfig::default())
+    }
+
+    /// Constructs controller with non-default `config`.
+    pub fn with_config(
+        service_bus: HashMap<B, BusConfig<B::Address>>,
+        handler: H,
+        config: ControllerConfig,
+    ) -> Result<Self, Error<B::Address>> {
+        let mut me = Self::empty(handler, Arc::new(R::create_unmarshaller()), config);
+        for (id, config) in service_bus {
+            me.add_service_bus(id, config)?;
+        }
+        Ok(me)
+    }
+
+    /// Constructs controller re-using existing `unmarshaller`, which may be
+    /// shared between multiple controllers and RPC clients.
+    pub fn with_unmarshaller(
+        service_bus: HashMap<B, BusConfig<B::Address>>,
+        handler: H,
+        unmarshaller: Arc<Unmarshaller<R>>,
+    ) -> Result<Self, Error<B::Address>> {
+        let mut me = Self::empty(handler, unmarshaller, ControllerConfig::default());
         for (id, config) in service_bus {
             me.add_service_bus(id, config)?;
         }
         Ok(me)
     }
 
+    /// Constructs controller from pre-built service bus sessions, which may be
+    /// used to run the controller over in-process sockets (for instance in
+    /// tests) bypassing ZMQ endpoint dialing.
+    pub fn from_sessions(
+        sessions: HashMap<B, LocalSession>,
+        router: Option<B::Address>,
+        handler: H,
+    ) -> Self {
+        Self::from_sessions_with_config(sessions, router, handler, ControllerConfig::default())
+    }
+
+    /// Constructs controller from pre-built service bus sessions in the same
+    /// way as [`Controller::from_sessions`], using non-default `config`.
+    pub fn from_sessions_with_config(
+        sessions: HashMap<B, LocalSession>,
+        router: Option<B::Address>,
+        handler: H,
+        config: ControllerConfig,
+    ) -> Self {
+        let mut me = Self::empty(handler, Arc::new(R::create_unmarshaller()), config);
+        for (id, session) in sessions {
+            me.add_session(id, session, router.clone());
+        }
+        me
+    }
+
+    fn empty(handler: H, unmarshaller: Arc<Unmarshaller<R>>, config: ControllerConfig) -> Self {
+        let dedup = match config.dedup_window {
+            0 => None,
+            window => Some(DedupFilter::with_window(window, config.dedup_ttl)),
+        };
+        Self {
+            endpoints: EndpointList::with_identity(handler.identity()),
+            unmarshaller,
+            handler,
+            dead_letter: None,
+            handler_stats: none!(),
+            watchdog: None,
+            next_request_id: 1,
+            frame_version: config.frame_version.clamp(1, ESB_FRAME_VERSION),
+            dedup,
+            clock: config.clock,
+            filters: none!(),
+        }
+    }
+
     pub fn add_service_bus(
         &mut self,
         id: B,
         config: BusConfig<B::Address>,
     ) -> Result<(), Error<B::Address>> {
-        let session = match config.carrier {
-            zeromq::Carrier::Locator(locator) => {
+        let (session, locator) = match config.carrier {
+            zeromq::Carrier::Locator(addr) => {
                 debug!(
                     "Creating ESB session for service {} located at {} with identity '{}'",
                     id,
-                    locator,
+                    addr,
                     self.handler.identity()
                 );
+                let locator = BusLocator {
+                    api_type: config.api_type,
+                    addr,
+                    socket_options: config.socket_options,
+                    queued: config.queued,
+                    topic: config.topic,
+                };
                 // TODO: Replace with RpcSession once its impl is completed
-                LocalSession::connect(
-                    config.api_type,
-                    &locator,
-                    None,
-                    Some(&self.handler.identity().into()),
-                    &ZMQ_CONTEXT,
-                )?
+                (locator.open(&self.handler.identity().into())?, Some(locator))
             }
             // TODO: Replace with RpcSession once its impl is completed
             zeromq::Carrier::Socket(socket) => {
                 debug!("Creating ESB session for service {}", &id);
+                // The socket is already connected, so options which must be set
+                // before the connection (like CURVE keys) are not effective
+                config.socket_options.apply(&socket)?;
                 // TODO: Replace with RpcSession once its impl is completed
-                LocalSession::with_zmq_socket(config.api_type, socket)
+                let session = LocalSession::with_zmq_socket(config.api_type, socket);
+                configure_session(
+                    &session,
+                    config.api_type,
+                    config.queued,
+                    config.topic.as_deref(),
+                )?;
+                (session, None)
             }
         };
-        if !config.queued {
-            session.as_socket().set_router_mandatory(true)?;
-        }
-        if config.api_type == ZmqSocketType::Sub {
-            session.as_socket().set_subscribe(config.topic.unwrap_or_default().as_bytes())?;
+        self.insert_endpoint(id, session, config.router, config.priority, config.versioned);
+        if let Some(endpoint) = self.endpoints.buses.get_mut(&id) {
+            endpoint.locator = locator;
         }
-        let router = match config.router {
+        Ok(())
+    }
+
+    /// Adds service bus using already constructed session. Unlike
+    /// [`Controller::add_service_bus`], the session is used as is, without
+    /// applying any socket options. The bus is not versioned.
+    pub fn add_session(&mut self, id: B, session: LocalSession, router: Option<B::Address>) {
+        self.insert_endpoint(id, session, router, false, false)
+    }
+
+    fn insert_endpoint(
+        &mut self,
+        id: B,
+        session: LocalSession,
+        router: Option<B::Address>,
+        priority: bool,
+        versioned: bool,
+    ) {
+        let router = match router {
             Some(router) if router == self.handler.identity() => None,
             router => router,
         };
-        self.endpoints.0.insert(id, Endpoint { session, router });
-        Ok(())
+        let endpoint = Endpoint {
+            session,
+            router,
+            priority,
+            versioned,
+            max_version: self.frame_version,
+            peer_versions: none!(),
+            announced: none!(),
+            locator: None,
+        };
+        self.endpoints.buses.insert(id, endpoint);
     }
 
     pub fn send_to(
@@ -233,22 +965,334 @@ where
         self.endpoints.send_to(bus_id, self.handler.identity(), dest, request)
     }
 
-    pub fn recv_poll(&mut self) -> Result<Vec<PollItem<B, R>>, Error<B::Address>> {
+    /// Sends the request on behalf of some other `source` identity instead of
+    /// the controller own one, allowing transparent proxying of messages by
+    /// gateway services.
+    pub fn send_to_as(
+        &mut self,
+        bus_id: B,
+        source: B::Address,
+        dest: B::Address,
+        request: R,
+    ) -> Result<(), Error<B::Address>> {
+        self.endpoints.send_to(bus_id, source, dest, request)
+    }
+
+    /// Sends already serialized request from the controller identity; see
+    /// [`EndpointList::send_encoded`] for the details.
+    pub fn send_encoded(
+        &mut self,
+        bus_id: B,
+        dest: B::Address,
+        data: &[u8],
+    ) -> Result<usize, Error<B::Address>> {
+        self.endpoints.send_encoded(bus_id, self.handler.identity(), dest, data)
+    }
+
+    /// Sends the same request from the controller identity to each of `dests`;
+    /// see [`EndpointList::send_to_many`] for the details.
+    pub fn send_to_many(
+        &mut self,
+        bus_id: B,
+        dests: impl IntoIterator<Item = B::Address>,
+        request: R,
+    ) -> Result<usize, Error<B::Address>> {
+        self.endpoints.send_to_many(bus_id, self.handler.identity(), dests, request)
+    }
+
+    /// Sends `request` to `dest` and blocks until the reply from `dest` arrives
+    /// via the same service bus, or fails with [`transport::Error::TimedOut`]
+    /// once `timeout` has passed according to the controller clock.
+    ///
+    /// The request is sent with a new request id, and the reply is the message
+    /// from `dest` echoing the id, which the controllers do when the handler
+    /// of `dest` sends its first message back to the controller from
+    /// [`Handler::handle`]. The ids are carried by the frames of version 2 and
+    /// above (see [`ESB_FRAME_VERSION`]), so the bus must be versioned and the
+    /// frame version agreed with the next hop must not be 1; otherwise the
+    /// function fails with [`Error::IncompatibleProtocolVersion`] (with
+    /// version 0 for the unversioned buses). If the next hop has not
+    /// announced its version yet, the request is sent once it does.
+    ///
+    /// Other messages received while waiting (including the late replies to
+    /// the timed out requests) are processed in the same way as by the run
+    /// loop, with their errors reported to [`Handler::handle_err`]. Messages
+    /// which can't be decoded (including the reply itself) are reported to
+    /// [`Handler::on_decode_error`], and the controller keeps waiting for the
+    /// reply. Since the handler has no access to the controller, the function
+    /// can't be called from [`Handler::handle`]; it also can't be used to send
+    /// requests to the controller itself.
+    pub fn request_reply(
+        &mut self,
+        bus_id: B,
+        dest: B::Address,
+        request: R,
+        timeout: Duration,
+    ) -> Result<R, Error<B::Address>> {
+        let identity = self.handler.identity();
+        let deadline = self.clock.now() + timeout;
+        // The request is sent once the frame version agreed with the next hop
+        // is known, which may require waiting for its announcement
+        let mut reply = None;
+        loop {
+            let endpoint = self
+                .endpoints
+                .buses
+                .get_mut(&bus_id)
+                .ok_or_else(|| Error::UnknownBusId(bus_id.to_string()))?;
+            if reply.is_none() {
+                if !endpoint.versioned {
+                    return Err(Error::IncompatibleProtocolVersion(dest, 0));
+                }
+                let hop = endpoint.next_hop(&identity, &dest);
+                match endpoint.peer_versions.get(&hop).copied() {
+                    Some(version) if version < 2 => {
+                        return Err(Error::IncompatibleProtocolVersion(dest, version));
+                    }
+                    Some(_) => {
+                        let request_id = self.next_request_id;
+                        self.next_request_id = request_id.wrapping_add(1);
+                        trace!("Sending {} to {} as request #{}", request, dest, request_id);
+                        let (source, target) = (identity.clone(), dest.clone());
+                        let correlation = Correlation::Request(request_id);
+                        let data = request.serialize();
+                        self.endpoints.send_serialized(bus_id, source, target, correlation, data)?;
+                        reply = Some(Correlation::Reply(request_id));
+                        continue;
+                    }
+                    None => {
+                        trace!("Awaiting ESB frame version announcement from {}", hop);
+                        endpoint.announce(&identity, &hop)?;
+                    }
+                }
+            }
+            let remaining = deadline.saturating_duration_since(self.clock.now());
+            let mut items = [endpoint.session.as_socket().as_poll_item(zmq::POLLIN)];
+            if zmq::poll(&mut items, remaining.as_millis().min(i64::MAX as u128) as i64)? == 0 {
+                return Err(transport::Error::TimedOut.into());
+            }
+            let routed_frame = endpoint.session.recv_routed_message()?;
+            if routed_frame.msg.is_empty() {
+                warn!("Ignoring empty ESB message received via {} bus", bus_id);
+                continue;
+            }
+            let hop = B::Address::from(routed_frame.hop);
+            let source = B::Address::from(routed_frame.src);
+            let target = B::Address::from(routed_frame.dst);
+            let msg = &routed_frame.msg;
+            let (correlation, data) = match endpoint.decode_frame(&hop, &source, &target, msg) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => continue,
+                Err(err) => {
+                    warn!(
+                        "Unable to decode ESB message from {} via {} bus: {}",
+                        source, bus_id, err
+                    );
+                    let raw = &routed_frame.msg;
+                    self.handler.on_decode_error(&mut self.endpoints, bus_id, source, raw, err)?;
+                    continue;
+                }
+            };
+            if source == dest && target == identity && Some(correlation) == reply {
+                match self.unmarshaller.unmarshall(Cursor::new(data)) {
+                    Ok(reply) => {
+                        debug!("{} -> {}: {}", source, target, reply);
+                        return Ok((*reply).clone());
+                    }
+                    Err(err) => {
+                        warn!("Unable to decode reply from {} via {} bus: {}", source, bus_id, err);
+                        let (endpoints, err) = (&mut self.endpoints, err.into());
+                        self.handler.on_decode_error(endpoints, bus_id, source, data, err)?;
+                        continue;
+                    }
+                }
+            }
+            trace!("Processing message from {} to {} while awaiting reply", source, target);
+            if let Err(err) = self.process_frame(bus_id, source, target, correlation, data) {
+                error!("ESB request processing error: {}", err);
+                self.handler.handle_err(&mut self.endpoints, err)?;
+            }
+        }
+    }
+
+    /// Replaces the controller handler, returning the previous one, such that
+    /// the service logic can be changed at runtime (for instance after the
+    /// configuration reload). Since the controller processes messages
+    /// sequentially, a handler call which is in progress always completes with
+    /// the old handler; all subsequent messages are handled by the new one.
+    ///
+    /// [`Handler::on_ready`] is not called for the new handler. If the new
+    /// handler has a different identity, the messages sent to the controller
+    /// itself use the new identity, but the service bus sockets keep the old
+    /// one until [`EndpointList::set_identity`] is called.
+    pub fn replace_handler(&mut self, handler: H) -> H {
+        self.endpoints.identity = Some(handler.identity());
+        std::mem::replace(&mut self.handler, handler)
+    }
+
+    /// Starts watchdog thread warning about [`Handler::handle`] calls which
+    /// have not completed within `timeout` according to the controller clock,
+    /// and calling `on_stuck` callback (if provided) once per each of such
+    /// calls. Replaces previously set watchdog, if any.
+    pub fn set_watchdog(
+        &mut self,
+        timeout: Duration,
+        on_stuck: Option<StuckHandlerCallback>,
+    ) -> Result<(), io::Error> {
+        self.watchdog = Some(Watchdog::spawn(timeout, on_stuck, self.clock.clone())?);
+        Ok(())
+    }
+
+    /// Sets dead-letter sink receiving messages which the controller fails to
+    /// route to their destination, so they can be inspected or replayed.
+    /// Without the sink, routing failures are reported to
+    /// [`Handler::handle_err`].
+    pub fn set_dead_letter_sink(
+        &mut self,
+        sink: impl FnMut(B, B::Address, B::Address, &[u8]) + Send + 'static,
+    ) {
+        self.dead_letter = Some(Box::new(sink));
+    }
+
+    /// Adds filter checking requests before they are passed to
+    /// [`Handler::handle`]. Filters are checked in the order they were added,
+    /// stopping at the first failed one, whose error is reported to
+    /// [`Handler::handle_err`].
+    pub fn add_filter(&mut self, filter: impl RequestFilter<B::Address, R> + Send + 'static) {
+        self.filters.push(Box::new(filter));
+    }
+
+    /// Enables retaining failed messages for retrying; see
+    /// [`EndpointList::set_send_buffer`] for the details.
+    pub fn set_send_buffer(&mut self, capacity: usize) { self.endpoints.set_send_buffer(capacity) }
+
+    /// Returns iterator over the retained messages which have failed to be
+    /// sent, starting from the oldest one
+    pub fn pending_sends(&self) -> impl Iterator<Item = &PendingSend<B>> + '_ {
+        self.endpoints.pending_sends()
+    }
+
+    /// Retries sending the retained failed messages, for instance after the
+    /// service bus was re-added with [`Controller::add_service_bus`]. Returns
+    /// the number of the messages which were sent successfully.
+    pub fn retry_pending(&mut self) -> usize { self.endpoints.retry_pending() }
+
+    /// Closes all service bus sessions, discarding the messages which were not
+    /// yet delivered. Unlike simple drop of the controller, which keeps the
+    /// linger configured for the sockets with [`SocketOptions::linger`]
+    /// (infinite by default), this can't hang the process exit on the
+    /// messages to unreachable peers.
+    pub fn close(mut self) { self.endpoints.close() }
+
+    /// Sends multipart message from the controller identity; see
+    /// [`EndpointList::send_to_multipart`] for the details.
+    pub fn send_to_multipart(
+        &mut self,
+        bus_id: B,
+        dest: B::Address,
+        parts: &[&[u8]],
+    ) -> Result<(), Error<B::Address>> {
+        self.endpoints.send_to_multipart(bus_id, self.handler.identity(), dest, parts)
+    }
+
+    /// Waits for incoming multipart messages sent with
+    /// [`EndpointList::send_to_multipart`], returning them without routing.
+    /// Messages sent to self are returned without waiting for the sockets.
+    pub fn recv_multipart(&mut self) -> Result<Vec<MultipartItem<B>>, Error<B::Address>> {
+        if !self.endpoints.loopback_multipart.is_empty() {
+            return Ok(self.endpoints.loopback_multipart.drain(..).collect());
+        }
         let mut vec = vec![];
         for bus_id in self.poll()? {
-            let sender = self.endpoints.0.get_mut(&bus_id).expect("must exist, just indexed");
-
+            let sender = self.endpoints.buses.get_mut(&bus_id).expect("must exist, just indexed");
             let routed_frame = sender.session.recv_routed_message()?;
-            let request = (*self.unmarshaller.unmarshall(Cursor::new(routed_frame.msg))?).clone();
+            let hop = B::Address::from(routed_frame.hop);
             let source = B::Address::from(routed_frame.src);
+            let dest = B::Address::from(routed_frame.dst);
+            let msg = match sender.decode_frame(&hop, &source, &dest, &routed_frame.msg)? {
+                Some((_, msg)) => msg,
+                None => continue,
+            };
+            match decode_parts(msg) {
+                Some(parts) => vec.push(MultipartItem { bus_id, source, dest, parts }),
+                None => warn!(
+                    "Ignoring malformed multipart message from {} received via {} bus",
+                    source, bus_id
+                ),
+            }
+        }
+        Ok(vec)
+    }
+
+    /// Waits for incoming messages, returning the decoded requests without
+    /// routing or passing them to the handler. Messages sent to self are
+    /// returned without waiting for the sockets. Messages which can't be
+    /// decoded are reported to [`Handler::on_decode_error`] and skipped, in the
+    /// same way as by the run loop.
+    pub fn recv_poll(&mut self) -> Result<Vec<PollItem<B, R>>, Error<B::Address>> {
+        let mut vec = vec![];
+        let mut failed = vec![];
+        if !self.endpoints.loopback.is_empty() {
+            while let Some((bus_id, source, data)) = self.endpoints.loopback.pop_front() {
+                match self.unmarshaller.unmarshall(Cursor::new(&data)) {
+                    Ok(request) => {
+                        vec.push(PollItem { bus_id, source, request: (*request).clone() })
+                    }
+                    Err(err) => failed.push((bus_id, source, data, err.into())),
+                }
+            }
+        } else {
+            for bus_id in self.poll()? {
+                let sender =
+                    self.endpoints.buses.get_mut(&bus_id).expect("must exist, just indexed");
 
-            vec.push(PollItem { bus_id, source, request });
+                let routed_frame = sender.session.recv_routed_message()?;
+                if routed_frame.msg.is_empty() {
+                    warn!("Ignoring empty ESB message received via {} bus", bus_id);
+                    continue;
+                }
+                let hop = B::Address::from(routed_frame.hop);
+                let source = B::Address::from(routed_frame.src);
+                let dest = B::Address::from(routed_frame.dst);
+                let msg = match sender.decode_frame(&hop, &source, &dest, &routed_frame.msg) {
+                    Ok(Some((_, msg))) => msg,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        failed.push((bus_id, source, routed_frame.msg, err));
+                        continue;
+                    }
+                };
+                match self.unmarshaller.unmarshall(Cursor::new(msg)) {
+                    Ok(request) => {
+                        vec.push(PollItem { bus_id, source, request: (*request).clone() })
+                    }
+                    Err(err) => failed.push((bus_id, source, msg.to_vec(), err.into())),
+                }
+            }
         }
 
+        for (bus_id, source, data, err) in failed {
+            warn!("Unable to decode ESB message from {} via {} bus: {}", source, bus_id, err);
+            self.handler.on_decode_error(&mut self.endpoints, bus_id, source, &data, err)?;
+        }
         Ok(vec)
     }
 }
 
+impl<B, R, H> Drop for Controller<B, R, H>
+where
+    R: Request,
+    B: BusId,
+    H: Handler<B, Request = R>,
+    Error<B::Address>: From<H::Error>,
+{
+    fn drop(&mut self) {
+        for bus_id in self.endpoints.bus_ids() {
+            debug!("Dropping ESB session for service bus {}", bus_id);
+        }
+    }
+}
+
 #[cfg(feature = "node")]
 impl<B, R, H> TryService for Controller<B, R, H>
 where
@@ -260,16 +1304,50 @@ where
     type ErrorType = Error<B::Address>;
 
     fn try_run_loop(mut self) -> Result<(), Self::ErrorType> {
-        self.handler.on_ready(&mut self.endpoints)?;
+        self.try_prepare()?;
         loop {
-            match self.run() {
-                Ok(_) => trace!("request processing complete"),
-                Err(err) => {
-                    error!("ESB request processing error: {}", err);
-                    self.handler.handle_err(&mut self.endpoints, err)?;
+            self.try_run_step()?;
+        }
+    }
+}
+
+#[cfg(feature = "node")]
+impl<B, R, H> TryServiceStep for Controller<B, R, H>
+where
+    R: Request,
+    B: BusId,
+    H: Handler<B, Request = R>,
+    Error<B::Address>: From<H::Error>,
+{
+    fn try_prepare(&mut self) -> Result<(), Self::ErrorType> {
+        Ok(self.handler.on_ready(&mut self.endpoints)?)
+    }
+
+    /// Processes either the next queued message or all messages received from
+    /// the service buses at once. Processing errors are reported to
+    /// [`Handler::handle_err`].
+    fn try_run_step(&mut self) -> Result<usize, Self::ErrorType> {
+        let (count, results) = match self.process_queued() {
+            Some(res) => (1, vec![res]),
+            None => match self.poll() {
+                Ok(bus_ids) => {
+                    let results = bus_ids
+                        .into_iter()
+                        .map(|bus_id| self.process(bus_id))
+                        .collect::<Vec<_>>();
+                    (results.len(), results)
                 }
+                Err(err) => (0, vec![Err(err)]),
+            },
+        };
+        for res in results {
+            if let Err(err) = res {
+                error!("ESB request processing error: {}", err);
+                self.handler.handle_err(&mut self.endpoints, err)?;
             }
         }
+        trace!("request processing complete");
+        Ok(count)
     }
 }
 
@@ -280,36 +1358,194 @@ where
     H: Handler<B, Request = R>,
     Error<B::Address>: From<H::Error>,
 {
-    #[cfg(feature = "node")]
-    fn run(&mut self) -> Result<(), Error<B::Address>> {
-        for bus_id in self.poll()? {
-            let sender = self.endpoints.0.get_mut(&bus_id).expect("must exist, just indexed");
+    /// Processes incoming messages until `n` of them are delivered to the
+    /// handler, blocking until they arrive, and returns the number of the
+    /// messages which were handled successfully.
+    ///
+    /// Unlike the service run loop, returns once `n` messages were delivered
+    /// to the handler; messages which are dropped (empty, repeated or
+    /// undecodable ones) or routed to other services are not counted. Errors
+    /// happening during message processing, including polling errors, are
+    /// reported to [`Handler::handle_err`] in the same way as by the run loop;
+    /// only errors returned by [`Handler::handle_err`] itself terminate the
+    /// function.
+    pub fn run_n(&mut self, n: usize) -> Result<usize, Error<B::Address>> {
+        let mut delivered = 0usize;
+        let mut handled = 0usize;
+        while delivered < n {
+            let calls = self.handler_stats.calls;
+            let (count, ok) = match self.process_queued() {
+                Some(res) => self.complete(calls, res)?,
+                None => match self.poll() {
+                    Err(err) => self.complete(calls, Err(err))?,
+                    Ok(bus_ids) => {
+                        let (mut count, mut ok) = (0usize, 0usize);
+                        for bus_id in bus_ids {
+                            if delivered + count == n {
+                                break;
+                            }
+                            let calls = self.handler_stats.calls;
+                            let res = self.process(bus_id);
+                            let (c, o) = self.complete(calls, res)?;
+                            count += c;
+                            ok += o;
+                        }
+                        (count, ok)
+                    }
+                },
+            };
+            delivered += count;
+            handled += ok;
+        }
+        Ok(handled)
+    }
 
-            let routed_frame = sender.session.recv_routed_message()?;
-            let request = (*self.unmarshaller.unmarshall(Cursor::new(routed_frame.msg))?).clone();
-            let source = B::Address::from(routed_frame.src);
-            let dest = B::Address::from(routed_frame.dst);
+    /// Reports message processing error (if any) to [`Handler::handle_err`],
+    /// returning whether the message was delivered to the handler (detected by
+    /// the change of the handler call counter from `calls`) and whether it
+    /// was handled successfully, as a pair of counts.
+    fn complete(
+        &mut self,
+        calls: u64,
+        res: Result<(), Error<B::Address>>,
+    ) -> Result<(usize, usize), Error<B::Address>> {
+        let delivered = (self.handler_stats.calls > calls) as usize;
+        match res {
+            Ok(()) => Ok((delivered, delivered)),
+            Err(err) => {
+                error!("ESB request processing error: {}", err);
+                self.handler.handle_err(&mut self.endpoints, err)?;
+                Ok((delivered, 0))
+            }
+        }
+    }
+
+    /// Handles the next message sent by the controller to itself, if any
+    fn process_queued(&mut self) -> Option<Result<(), Error<B::Address>>> {
+        let (bus_id, source, data) = self.endpoints.loopback.pop_front()?;
+        Some(self.deliver_loopback(bus_id, source, data))
+    }
+
+    fn deliver_loopback(
+        &mut self,
+        bus_id: B,
+        source: B::Address,
+        data: Vec<u8>,
+    ) -> Result<(), Error<B::Address>> {
+        let request = (*self.unmarshaller.unmarshall(Cursor::new(data))?).clone();
+        debug!("{} -> self: {}", source, request);
+        self.handle(bus_id, source, Correlation::None, request)
+    }
+
+    fn handle(
+        &mut self,
+        bus_id: B,
+        source: B::Address,
+        correlation: Correlation,
+        request: R,
+    ) -> Result<(), Error<B::Address>> {
+        if let Err(err) = self.filters.iter().try_for_each(|f| f.check(&source, &request)) {
+            debug!("ESB request from {} is rejected by the filter: {}", source, err);
+            return Err(err);
+        }
+        let start = self.clock.now();
+        if let Some(ref watchdog) = self.watchdog {
+            watchdog.enter(start);
+        }
+        self.endpoints.replying_to = match correlation {
+            Correlation::Request(id) => Some((source.clone(), id)),
+            _ => None,
+        };
+        let res = self.handler.handle(&mut self.endpoints, bus_id, source, request);
+        self.endpoints.replying_to = None;
+        if let Some(ref watchdog) = self.watchdog {
+            watchdog.leave();
+        }
+        self.handler_stats.record(self.clock.now().saturating_duration_since(start));
+        Ok(res?)
+    }
 
-            if dest == self.handler.identity() {
-                // We are the destination
-                debug!("{} -> {}: {}", source, dest, request);
+    fn process(&mut self, bus_id: B) -> Result<(), Error<B::Address>> {
+        let sender = self.endpoints.buses.get_mut(&bus_id).expect("must exist, just indexed");
 
-                self.handler.handle(&mut self.endpoints, bus_id, source, request)?;
-            } else {
-                // Need to route
-                trace!("Routing {} from {} to {}", request, source, dest);
-                self.endpoints.send_to(bus_id, source, dest, request)?
+        let routed_frame = sender.session.recv_routed_message()?;
+        if routed_frame.msg.is_empty() {
+            // Misbehaving peers must not be able to break the run loop
+            warn!("Ignoring empty ESB message received via {} bus", bus_id);
+            return Ok(());
+        }
+        let hop = B::Address::from(routed_frame.hop);
+        let source = B::Address::from(routed_frame.src);
+        let dest = B::Address::from(routed_frame.dst);
+        match sender.decode_frame(&hop, &source, &dest, &routed_frame.msg)? {
+            Some((correlation, msg)) => self.process_frame(bus_id, source, dest, correlation, msg),
+            // Version announcement, which is not a message
+            None => Ok(()),
+        }
+    }
+
+    fn process_frame(
+        &mut self,
+        bus_id: B,
+        source: B::Address,
+        dest: B::Address,
+        correlation: Correlation,
+        msg: &[u8],
+    ) -> Result<(), Error<B::Address>> {
+        if let Some(ref mut dedup) = self.dedup {
+            if dedup.is_duplicate(&source, &dest, msg, self.clock.now()) {
+                debug!("Dropping repeated ESB message from {} via {} bus", source, bus_id);
+                return Ok(());
             }
         }
 
-        Ok(())
+        #[cfg(feature = "tracing")]
+        let _span = debug_span!("esb", bus_id = %bus_id, source = %source).entered();
+
+        if dest != self.handler.identity() {
+            // Need to route. Messages are routed as is, without decoding, so
+            // routers may pass messages of the APIs unknown to them (including
+            // multipart messages) and keep their correlation.
+            trace!("Routing {} bytes from {} to {}", msg.len(), source, dest);
+            let (src, dst, data) = (source.clone(), dest.clone(), msg.to_vec());
+            let res = self.endpoints.send_serialized(bus_id, src, dst, correlation, data);
+            match (res, &mut self.dead_letter) {
+                (Err(err), Some(sink)) => {
+                    warn!("Passing unroutable message to the dead-letter sink: {}", err);
+                    sink(bus_id, source, dest, msg);
+                }
+                (res, _) => {
+                    res?;
+                }
+            }
+            return Ok(());
+        }
+
+        let request = match self.unmarshaller.unmarshall(Cursor::new(msg)) {
+            Ok(request) => (*request).clone(),
+            Err(err) => {
+                warn!("Unable to decode ESB message from {} via {} bus: {}", source, bus_id, err);
+                self.handler.on_decode_error(
+                    &mut self.endpoints,
+                    bus_id,
+                    source,
+                    msg,
+                    err.into(),
+                )?;
+                return Ok(());
+            }
+        };
+
+        // We are the destination
+        debug!("{} -> {}: {}", source, dest, request);
+        self.handle(bus_id, source, correlation, request)
     }
 
     fn poll(&mut self) -> Result<Vec<B>, Error<B::Address>> {
         let mut index = vec![];
         let mut items = self
             .endpoints
-            .0
+            .buses
             .iter()
             .map(|(service, sender)| {
                 index.push(service);
@@ -320,7 +1556,7 @@ where
         trace!("Awaiting for ESB request from {} service buses...", items.len());
         let _ = zmq::poll(&mut items, -1)?;
 
-        let service_buses = items
+        let mut service_buses = items
             .iter()
             .enumerate()
             .filter_map(
@@ -333,9 +1569,637 @@ where
                 },
             )
             .collect::<Vec<_>>();
+        // Control messages from priority buses must not wait for the bulk
+        // traffic to be processed
+        service_buses.sort_by_key(|bus_id| !self.endpoints.buses[bus_id].priority);
 
         trace!("Received ESB request from {} service busses...", service_buses.len());
 
         Ok(service_buses)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+    use std::thread;
+
+    use internet2::{CreateUnmarshaller, TypedEnum};
+
+    use super::*;
+    use crate::esb::test::{router_pair, router_sockets, send_routed, Addr, Recorder, TestBus};
+    use crate::rpc::test::TestRequest;
+    use crate::rpc::ErrorKind;
+    use crate::util::MockClock;
+
+    fn controller(
+        name: &str,
+        handler: Recorder,
+    ) -> (Controller<TestBus, TestRequest, Recorder>, LocalSession) {
+        let (session, peer) = router_pair(name, handler.identity(), Addr::Peer);
+        let controller = Controller::from_sessions(map! { TestBus::Msg => session }, None, handler);
+        (controller, peer)
+    }
+
+    #[test]
+    fn run_n_counts_delivered_messages() {
+        let handler = Recorder::new(Addr::Daemon);
+        let handled = handler.handled.clone();
+        let (session, mut peer) = router_pair("run-n", handler.identity(), Addr::Peer);
+        let config = ControllerConfig { dedup_window: 8, ..ControllerConfig::default() };
+        let sessions = map! { TestBus::Msg => session };
+        let mut controller = Controller::from_sessions_with_config(sessions, None, handler, config);
+
+        let ping = |nonce| TestRequest::Ping(nonce).serialize();
+        send_routed(&mut peer, Addr::Peer, Addr::Daemon, Addr::Daemon, &ping(1));
+        send_routed(&mut peer, Addr::Peer, Addr::Daemon, Addr::Daemon, &ping(1));
+        send_routed(&mut peer, Addr::Peer, Addr::Daemon, Addr::Daemon, &[]);
+        send_routed(&mut peer, Addr::Peer, Addr::Daemon, Addr::Daemon, &ping(2));
+        send_routed(&mut peer, Addr::Peer, Addr::Daemon, Addr::Daemon, &ping(3));
+
+        assert_eq!(controller.run_n(3).unwrap(), 3);
+        assert_eq!(Recorder::requests(&handled), vec![
+            TestRequest::Ping(1),
+            TestRequest::Ping(2),
+            TestRequest::Ping(3)
+        ]);
+        assert_eq!(controller.handler_stats().calls, 3);
+    }
+
+    #[test]
+    fn dedup_window() {
+        let handler = Recorder::new(Addr::Daemon);
+        let handled = handler.handled.clone();
+        let (session, mut peer) = router_pair("dedup", handler.identity(), Addr::Peer);
+        let clock = MockClock::new();
+        let config = ControllerConfig {
+            dedup_window: 3,
+            dedup_ttl: Some(Duration::from_secs(10)),
+            clock: Arc::new(clock.clone()),
+            ..ControllerConfig::default()
+        };
+        let sessions = map! { TestBus::Msg => session };
+        let mut controller = Controller::from_sessions_with_config(sessions, None, handler, config);
+
+        let mut ping = |nonce| {
+            let msg = TestRequest::Ping(nonce).serialize();
+            send_routed(&mut peer, Addr::Peer, Addr::Daemon, Addr::Daemon, &msg);
+        };
+        // The first message is pushed out of the window by the next three
+        for nonce in [1, 1, 2, 3, 4, 1] {
+            ping(nonce);
+        }
+        assert_eq!(controller.run_n(5).unwrap(), 5);
+
+        clock.advance(Duration::from_secs(5));
+        ping(4);
+        ping(5);
+        assert_eq!(controller.run_n(1).unwrap(), 1);
+
+        // The message is forgotten once the time to live expires
+        clock.advance(Duration::from_secs(6));
+        ping(4);
+        assert_eq!(controller.run_n(1).unwrap(), 1);
+
+        let nonces = Recorder::requests(&handled)
+            .into_iter()
+            .map(|request| match request {
+                TestRequest::Ping(nonce) => nonce,
+                request => panic!("unexpected request {}", request),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(nonces, vec![1, 2, 3, 4, 1, 5, 4]);
+    }
+
+    #[test]
+    fn filter_rejects_request() {
+        let handler = Recorder::new(Addr::Daemon);
+        let (handled, errors) = (handler.handled.clone(), handler.errors.clone());
+        let (mut controller, mut peer) = controller("filter", handler);
+        controller.add_filter(|source: &Addr, request: &TestRequest| match request {
+            TestRequest::Ping(1) => Err(Error::ServiceError(format!("{} may not ping", source))),
+            _ => Ok(()),
+        });
+
+        for nonce in [1, 2] {
+            let msg = TestRequest::Ping(nonce).serialize();
+            send_routed(&mut peer, Addr::Peer, Addr::Daemon, Addr::Daemon, &msg);
+        }
+        assert_eq!(controller.run_n(1).unwrap(), 1);
+        assert_eq!(Recorder::requests(&handled), vec![TestRequest::Ping(2)]);
+        assert_eq!(*errors.lock().unwrap(), vec![format!("{} may not ping", Addr::Peer)]);
+        assert_eq!(controller.handler_stats().calls, 1);
+    }
+
+    #[test]
+    fn send_to_counted_returns_payload_size() {
+        let request = TestRequest::Echo(s!("payload"));
+        let size = request.serialize().len();
+        for (name, versioned) in [("counted", false), ("counted-versioned", true)] {
+            let (socket, _peer) = router_sockets(name, Addr::Daemon, Addr::Peer);
+            let mut config = BusConfig::with_socket(socket, ZmqSocketType::RouterBind, None);
+            config.versioned = versioned;
+            let mut controller =
+                Controller::with(map! { TestBus::Msg => config }, Recorder::new(Addr::Daemon))
+                    .unwrap();
+            let sent = controller.endpoints.send_to_counted(
+                TestBus::Msg,
+                Addr::Daemon,
+                Addr::Peer,
+                request.clone(),
+            );
+            assert_eq!(sent.unwrap(), size);
+            // Messages sent to self are counted in the same way
+            let sent = controller.endpoints.send_to_counted(
+                TestBus::Msg,
+                Addr::Daemon,
+                Addr::Daemon,
+                request.clone(),
+            );
+            assert_eq!(sent.unwrap(), size);
+        }
+    }
+
+    #[test]
+    fn set_identity_keeps_socket_options() {
+        let endpoint = ServiceAddr::Inproc(s!("set-identity"));
+        let server = ZMQ_CONTEXT.socket(zmq::ROUTER).unwrap();
+        server.bind(&endpoint.zmq_connect_string()).unwrap();
+
+        let mut config = BusConfig::with_addr(endpoint, ZmqSocketType::RouterConnect, None);
+        config.socket_options.sndhwm = Some(7);
+        let mut controller = Controller::<TestBus, TestRequest, _>::with(
+            map! { TestBus::Msg => config },
+            Recorder::new(Addr::Daemon),
+        )
+        .unwrap();
+        controller.endpoints.set_identity(TestBus::Msg, Addr::Peer).unwrap();
+
+        let socket = controller.endpoints.buses[&TestBus::Msg].session.as_socket();
+        assert_eq!(socket.get_identity().unwrap(), Vec::<u8>::from(Addr::Peer));
+        assert_eq!(socket.get_sndhwm().unwrap(), 7);
+    }
+
+    #[test]
+    fn priority_bus_processed_first() {
+        let handler = Recorder::new(Addr::Daemon);
+        let handled = handler.handled.clone();
+        let (bulk, bulk_peer) = router_sockets("priority-bulk", Addr::Daemon, Addr::Peer);
+        let (ctl, ctl_peer) = router_sockets("priority-ctl", Addr::Daemon, Addr::Peer);
+        let mut ctl = BusConfig::with_socket(ctl, ZmqSocketType::RouterBind, None);
+        ctl.priority = true;
+        let mut controller = Controller::<TestBus, TestRequest, _>::with(
+            map! {
+                TestBus::Msg => BusConfig::with_socket(bulk, ZmqSocketType::RouterBind, None),
+                TestBus::Ctl => ctl
+            },
+            handler,
+        )
+        .unwrap();
+
+        let mut bulk_peer = LocalSession::with_zmq_socket(ZmqSocketType::RouterConnect, bulk_peer);
+        let mut ctl_peer = LocalSession::with_zmq_socket(ZmqSocketType::RouterConnect, ctl_peer);
+        for nonce in 1..=10 {
+            let msg = TestRequest::Ping(nonce).serialize();
+            send_routed(&mut bulk_peer, Addr::Peer, Addr::Daemon, Addr::Daemon, &msg);
+        }
+        let msg = TestRequest::Ping(0).serialize();
+        send_routed(&mut ctl_peer, Addr::Peer, Addr::Daemon, Addr::Daemon, &msg);
+
+        assert_eq!(controller.run_n(11).unwrap(), 11);
+        let handled = handled.lock().unwrap();
+        assert_eq!(handled[0], (TestBus::Ctl, Addr::Peer, TestRequest::Ping(0)));
+        assert!(handled[1..].iter().all(|(bus_id, ..)| *bus_id == TestBus::Msg));
+    }
+
+    #[test]
+    fn loopback_delivery() {
+        let handler = Recorder::with_hook(Addr::Daemon, |endpoints, bus_id, request| {
+            if let TestRequest::Ping(1) = request {
+                // Deferred work sent by the handler to itself; sending it
+                // over the socket would fail, since no peer on the bus has
+                // the controller identity
+                endpoints
+                    .send_to(bus_id, Addr::Daemon, Addr::Daemon, TestRequest::Ping(2))
+                    .unwrap();
+            }
+        });
+        let handled = handler.handled.clone();
+        let (mut controller, mut peer) = controller("loopback", handler);
+
+        let msg = TestRequest::Ping(1).serialize();
+        send_routed(&mut peer, Addr::Peer, Addr::Daemon, Addr::Daemon, &msg);
+        assert_eq!(controller.run_n(2).unwrap(), 2);
+
+        assert_eq!(*handled.lock().unwrap(), vec![
+            (TestBus::Msg, Addr::Peer, TestRequest::Ping(1)),
+            (TestBus::Msg, Addr::Daemon, TestRequest::Ping(2))
+        ]);
+    }
+
+    #[test]
+    fn recv_poll_reports_undecodable_messages() {
+        let handler = Recorder::new(Addr::Daemon);
+        let errors = handler.errors.clone();
+        let (mut controller, mut peer) = controller("recv-poll", handler);
+
+        controller.send_encoded(TestBus::Msg, Addr::Daemon, &[0xFF, 0xFF]).unwrap();
+        controller.send_to(TestBus::Msg, Addr::Daemon, TestRequest::Ping(1)).unwrap();
+        let items = controller.recv_poll().unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].request, TestRequest::Ping(1));
+        assert_eq!(errors.lock().unwrap().len(), 1);
+
+        send_routed(&mut peer, Addr::Peer, Addr::Daemon, Addr::Daemon, &[0xFF, 0xFF]);
+        assert!(controller.recv_poll().unwrap().is_empty());
+        assert_eq!(errors.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn watchdog_reports_stuck_handler() {
+        let clock = MockClock::new();
+        let handler_clock = clock.clone();
+        let handler = Recorder::with_hook(Addr::Daemon, move |_, _, request| {
+            if let TestRequest::Ping(1) = request {
+                handler_clock.advance(Duration::from_secs(200));
+                // Gives the watchdog thread time to notice the blocked handler
+                thread::sleep(Duration::from_millis(300));
+            }
+        });
+        let (session, mut peer) = router_pair("watchdog", handler.identity(), Addr::Peer);
+        let config = ControllerConfig { clock: Arc::new(clock), ..ControllerConfig::default() };
+        let sessions = map! { TestBus::Msg => session };
+        let mut controller = Controller::from_sessions_with_config(sessions, None, handler, config);
+        let stuck = Arc::new(Mutex::new(Vec::<Duration>::new()));
+        let reports = stuck.clone();
+        let on_stuck = move |blocked| reports.lock().unwrap().push(blocked);
+        controller.set_watchdog(Duration::from_secs(50), Some(Box::new(on_stuck))).unwrap();
+
+        for nonce in [1, 2] {
+            let msg = TestRequest::Ping(nonce).serialize();
+            send_routed(&mut peer, Addr::Peer, Addr::Daemon, Addr::Daemon, &msg);
+        }
+        assert_eq!(controller.run_n(2).unwrap(), 2);
+
+        // Only the slow call is reported, and only once
+        assert_eq!(*stuck.lock().unwrap(), vec![Duration::from_secs(200)]);
+        let stats = controller.handler_stats();
+        assert_eq!(stats.calls, 2);
+        assert_eq!(stats.max, Duration::from_secs(200));
+        assert_eq!(stats.last, Duration::ZERO);
+    }
+
+    /// Processes messages available on the controller buses once, routing the
+    /// messages destined to other services
+    fn route_once(controller: &mut Controller<TestBus, TestRequest, Recorder>) {
+        for bus_id in controller.poll().unwrap() {
+            controller.process(bus_id).unwrap();
+        }
+    }
+
+    #[test]
+    fn multipart_via_router() {
+        let name = "multipart";
+        let (router, sender) = router_sockets(name, Addr::Router, Addr::Peer);
+        let receiver = ZMQ_CONTEXT.socket(zmq::ROUTER).unwrap();
+        receiver.set_identity(&Vec::from(Addr::Wallet)).unwrap();
+        receiver.connect(&format!("inproc://{}", name)).unwrap();
+
+        let bus = |socket, api_type, router| {
+            let mut config = BusConfig::with_socket(socket, api_type, router);
+            config.versioned = true;
+            map! { TestBus::Msg => config }
+        };
+        let mut router = Controller::<TestBus, TestRequest, _>::with(
+            bus(router, ZmqSocketType::RouterBind, None),
+            Recorder::new(Addr::Router),
+        )
+        .unwrap();
+        let mut sender = Controller::<TestBus, TestRequest, _>::with(
+            bus(sender, ZmqSocketType::RouterConnect, Some(Addr::Router)),
+            Recorder::new(Addr::Peer),
+        )
+        .unwrap();
+        let mut receiver = Controller::<TestBus, TestRequest, _>::with(
+            bus(receiver, ZmqSocketType::RouterConnect, Some(Addr::Router)),
+            Recorder::new(Addr::Wallet),
+        )
+        .unwrap();
+
+        let parts: [&[u8]; 3] = [b"header", b"", b"body"];
+        let mut attempts = 0;
+        while let Err(err) = sender.send_to_multipart(TestBus::Msg, Addr::Wallet, &parts) {
+            // Waiting for the connection with the router to be established
+            attempts += 1;
+            assert!(attempts < 100, "unable to send multipart message: {}", err);
+            thread::sleep(Duration::from_millis(10));
+        }
+        // The message is preceded by the frame version announcement of the
+        // sender, and then of the router
+        route_once(&mut router);
+        route_once(&mut router);
+        assert!(receiver.recv_multipart().unwrap().is_empty());
+
+        let items = receiver.recv_multipart().unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].bus_id, TestBus::Msg);
+        assert_eq!(items[0].source, Addr::Peer);
+        assert_eq!(items[0].dest, Addr::Wallet);
+        assert_eq!(items[0].parts, parts.map(<[u8]>::to_vec));
+    }
+
+    #[test]
+    fn multipart_loopback() {
+        let (mut controller, _peer) = controller("multipart-loopback", Recorder::new(Addr::Daemon));
+        controller.send_to_multipart(TestBus::Msg, Addr::Daemon, &[b"head", b"body"]).unwrap();
+        let items = controller.recv_multipart().unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].source, Addr::Daemon);
+        assert_eq!(items[0].parts, vec![b"head".to_vec(), b"body".to_vec()]);
+    }
+
+    #[test]
+    fn multipart_encoding() {
+        let parts: [&[u8]; 3] = [b"", b"\x00\x01", b"part"];
+        let data = encode_parts(&parts);
+        assert_eq!(decode_parts(&data), Some(parts.map(<[u8]>::to_vec).to_vec()));
+        assert_eq!(decode_parts(&encode_parts(&[])), Some(vec![]));
+        assert_eq!(decode_parts(&data[..data.len() - 1]), None);
+        assert_eq!(decode_parts(&[data.as_slice(), &[0]].concat()), None);
+        assert_eq!(decode_parts(&[0xFF; 4]), None);
+        assert_eq!(decode_parts(&[]), None);
+    }
+
+    /// Creates controller with versioned service bus connected to the returned
+    /// raw session of [`Addr::Peer`], which has negotiated frame version 2
+    /// with the controller
+    fn versioned_controller(
+        name: &str,
+        handler: Recorder,
+    ) -> (Controller<TestBus, TestRequest, Recorder>, LocalSession) {
+        let (socket, peer) = router_sockets(name, handler.identity(), Addr::Peer);
+        let mut config = BusConfig::with_socket(socket, ZmqSocketType::RouterBind, None);
+        config.versioned = true;
+        let mut controller = Controller::with(map! { TestBus::Msg => config }, handler).unwrap();
+        let mut peer = LocalSession::with_zmq_socket(ZmqSocketType::RouterConnect, peer);
+        let identity = controller.handler.identity();
+        send_routed(&mut peer, Addr::Peer, identity.clone(), identity, &[0, 2]);
+        route_once(&mut controller);
+        assert_eq!(peer.recv_routed_message().unwrap().msg, vec![0, 2]);
+        (controller, peer)
+    }
+
+    /// Encodes message into version 2 frame with the given correlation
+    fn frame(correlation: Correlation, msg: &[u8]) -> Vec<u8> {
+        let mut frame = vec![2];
+        encode_correlation(correlation, &mut frame);
+        frame.extend_from_slice(msg);
+        frame
+    }
+
+    /// Receives request sent by [`Controller::request_reply`] with the raw
+    /// peer session, returning the request id and the request itself
+    fn recv_request(peer: &mut LocalSession) -> (u64, TestRequest) {
+        let frame = peer.recv_routed_message().unwrap();
+        assert_eq!(frame.msg[0], 2);
+        match decode_correlation(&frame.msg[1..]) {
+            Some((Correlation::Request(id), data)) => {
+                let unmarshaller = TestRequest::create_unmarshaller();
+                (id, (*unmarshaller.unmarshall(Cursor::new(data)).unwrap()).clone())
+            }
+            other => panic!("unexpected frame {:?}", other.map(|(correlation, _)| correlation)),
+        }
+    }
+
+    #[test]
+    fn request_reply_skips_undecodable_messages() {
+        let handler = Recorder::new(Addr::Daemon);
+        let (handled, errors) = (handler.handled.clone(), handler.errors.clone());
+        let (mut controller, mut peer) = versioned_controller("request-reply", handler);
+
+        let responder = thread::spawn(move || {
+            let (id, request) = recv_request(&mut peer);
+            // Unrelated message, which must be handled while waiting
+            let unrelated = TestRequest::Echo(s!("unrelated")).serialize();
+            let unrelated = frame(Correlation::None, &unrelated);
+            send_routed(&mut peer, Addr::Wallet, Addr::Daemon, Addr::Daemon, &unrelated);
+            // Malformed reply, which must be skipped
+            let malformed = frame(Correlation::Reply(id), &[0xFF, 0xFF]);
+            send_routed(&mut peer, Addr::Peer, Addr::Daemon, Addr::Daemon, &malformed);
+            // ESB replies are of the same type as the requests
+            let reply = frame(Correlation::Reply(id), &request.serialize());
+            send_routed(&mut peer, Addr::Peer, Addr::Daemon, Addr::Daemon, &reply);
+        });
+        let reply = controller
+            .request_reply(TestBus::Msg, Addr::Peer, TestRequest::Ping(1), Duration::from_secs(5))
+            .unwrap();
+        responder.join().unwrap();
+
+        assert_eq!(reply, TestRequest::Ping(1));
+        assert_eq!(errors.lock().unwrap().len(), 1);
+        assert_eq!(*handled.lock().unwrap(), vec![(
+            TestBus::Msg,
+            Addr::Wallet,
+            TestRequest::Echo(s!("unrelated"))
+        )]);
+    }
+
+    #[test]
+    fn request_reply_is_correlated() {
+        let handler = Recorder::new(Addr::Daemon);
+        let handled = handler.handled.clone();
+        let (mut controller, mut peer) = versioned_controller("request-reply-correlated", handler);
+        let timeout = Duration::from_millis(100);
+        assert_eq!(
+            controller
+                .request_reply(TestBus::Msg, Addr::Peer, TestRequest::Ping(1), timeout)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::TimedOut
+        );
+        let (stale, _) = recv_request(&mut peer);
+
+        let responder = thread::spawn(move || {
+            let (id, request) = recv_request(&mut peer);
+            assert_ne!(id, stale);
+            // Unrelated message and the late reply to the timed out request
+            // arrive from the destination before the reply
+            let unrelated = TestRequest::Echo(s!("unrelated")).serialize();
+            let late = TestRequest::Ping(1).serialize();
+            for msg in [
+                frame(Correlation::None, &unrelated),
+                frame(Correlation::Reply(stale), &late),
+                frame(Correlation::Reply(id), &request.serialize()),
+            ] {
+                send_routed(&mut peer, Addr::Peer, Addr::Daemon, Addr::Daemon, &msg);
+            }
+        });
+        let reply = controller
+            .request_reply(TestBus::Msg, Addr::Peer, TestRequest::Ping(2), Duration::from_secs(5))
+            .unwrap();
+        responder.join().unwrap();
+
+        assert_eq!(reply, TestRequest::Ping(2));
+        assert_eq!(Recorder::requests(&handled), vec![
+            TestRequest::Echo(s!("unrelated")),
+            TestRequest::Ping(1)
+        ]);
+    }
+
+    #[test]
+    fn request_reply_between_controllers() {
+        let (socket, peer) = router_sockets("request-reply-controllers", Addr::Daemon, Addr::Peer);
+        let bus = |socket, api_type| {
+            let mut config = BusConfig::with_socket(socket, api_type, None);
+            config.versioned = true;
+            map! { TestBus::Msg => config }
+        };
+        let responder = thread::spawn(move || {
+            // Only the first message sent back to the source is the reply
+            let handler = Recorder::with_hook(Addr::Peer, |endpoints, bus_id, request| {
+                let reply = TestRequest::Echo(request.to_string());
+                endpoints.send_to(bus_id, Addr::Peer, Addr::Daemon, reply).unwrap();
+                let notice = TestRequest::Echo(s!("notice"));
+                endpoints.send_to(bus_id, Addr::Peer, Addr::Daemon, notice).unwrap();
+            });
+            let mut responder =
+                Controller::with(bus(peer, ZmqSocketType::RouterConnect), handler).unwrap();
+            responder.run_n(1).unwrap()
+        });
+
+        let handler = Recorder::new(Addr::Daemon);
+        let handled = handler.handled.clone();
+        let mut requester =
+            Controller::with(bus(socket, ZmqSocketType::RouterBind), handler).unwrap();
+        let request = TestRequest::Ping(7);
+        let timeout = Duration::from_secs(5);
+        let reply = loop {
+            match requester.request_reply(TestBus::Msg, Addr::Peer, request.clone(), timeout) {
+                // Waiting for the connection to be established
+                Err(Error::Send(..)) => thread::sleep(Duration::from_millis(10)),
+                res => break res.unwrap(),
+            }
+        };
+        assert_eq!(responder.join().unwrap(), 1);
+
+        assert_eq!(reply, TestRequest::Echo(request.to_string()));
+        assert_eq!(requester.run_n(1).unwrap(), 1);
+        assert_eq!(Recorder::requests(&handled), vec![TestRequest::Echo(s!("notice"))]);
+    }
+
+    #[test]
+    fn frame_version_negotiation() {
+        let (socket, peer) = router_sockets("frame-version", Addr::Daemon, Addr::Peer);
+        let bus = |socket, api_type| {
+            let mut config = BusConfig::with_socket(socket, api_type, None);
+            config.versioned = true;
+            map! { TestBus::Msg => config }
+        };
+        let handler = Recorder::new(Addr::Daemon);
+        let handled = handler.handled.clone();
+        let mut current =
+            Controller::with(bus(socket, ZmqSocketType::RouterBind), handler).unwrap();
+        // Controller which does not support version 2 frames
+        let handler = Recorder::new(Addr::Peer);
+        let old_handled = handler.handled.clone();
+        let config = ControllerConfig { frame_version: 1, ..ControllerConfig::default() };
+        let mut old =
+            Controller::with_config(bus(peer, ZmqSocketType::RouterConnect), handler, config)
+                .unwrap();
+
+        let ping = TestRequest::Ping(1);
+        while let Err(Error::Send(..)) = current.send_to(TestBus::Msg, Addr::Peer, ping.clone()) {
+            // Waiting for the connection to be established
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(old.run_n(1).unwrap(), 1);
+        old.send_to(TestBus::Msg, Addr::Daemon, TestRequest::Ping(2)).unwrap();
+        assert_eq!(current.run_n(1).unwrap(), 1);
+        current.send_to(TestBus::Msg, Addr::Peer, TestRequest::Ping(3)).unwrap();
+        assert_eq!(old.run_n(1).unwrap(), 1);
+
+        assert_eq!(Recorder::requests(&old_handled), vec![
+            TestRequest::Ping(1),
+            TestRequest::Ping(3)
+        ]);
+        assert_eq!(Recorder::requests(&handled), vec![TestRequest::Ping(2)]);
+        assert_eq!(current.endpoints.buses[&TestBus::Msg].peer_versions[&Addr::Peer], 1);
+        assert_eq!(old.endpoints.buses[&TestBus::Msg].peer_versions[&Addr::Daemon], 1);
+
+        // Replies can't be correlated with version 1 frames
+        let timeout = Duration::from_secs(1);
+        let err = current
+            .request_reply(TestBus::Msg, Addr::Peer, TestRequest::Ping(4), timeout)
+            .unwrap_err();
+        assert_eq!(err.to_string(), Error::IncompatibleProtocolVersion(Addr::Peer, 1).to_string());
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn unsupported_frame_version_rejected() {
+        let (socket, peer) = router_sockets("frame-version-unsupported", Addr::Daemon, Addr::Peer);
+        let mut bus = BusConfig::with_socket(socket, ZmqSocketType::RouterBind, None);
+        bus.versioned = true;
+        let config = ControllerConfig { frame_version: 1, ..ControllerConfig::default() };
+        let handler = Recorder::new(Addr::Daemon);
+        let mut controller =
+            Controller::with_config(map! { TestBus::Msg => bus }, handler, config).unwrap();
+        let mut peer = LocalSession::with_zmq_socket(ZmqSocketType::RouterConnect, peer);
+
+        let msg = frame(Correlation::None, &TestRequest::Ping(1).serialize());
+        send_routed(&mut peer, Addr::Peer, Addr::Daemon, Addr::Daemon, &msg);
+        let bus_id = controller.poll().unwrap()[0];
+        let err = controller.process(bus_id).unwrap_err();
+        assert_eq!(err.to_string(), Error::IncompatibleProtocolVersion(Addr::Peer, 2).to_string());
+        // The peer is told which version to fall back to
+        assert_eq!(peer.recv_routed_message().unwrap().msg, vec![0, 1]);
+    }
+
+    #[test]
+    fn retry_pending_after_reconnect() {
+        let endpoint = "inproc://retry-pending";
+        let socket = ZMQ_CONTEXT.socket(zmq::ROUTER).unwrap();
+        socket.set_identity(&Vec::from(Addr::Daemon)).unwrap();
+        socket.bind(endpoint).unwrap();
+        let config = BusConfig::with_socket(socket, ZmqSocketType::RouterBind, None);
+        let mut controller = Controller::<TestBus, TestRequest, _>::with(
+            map! { TestBus::Msg => config },
+            Recorder::new(Addr::Daemon),
+        )
+        .unwrap();
+        controller.set_send_buffer(2);
+
+        // The peer is not connected, so the messages are retained, with the
+        // oldest one discarded once the buffer is full
+        for nonce in 1..=3 {
+            let res = controller.send_to(TestBus::Msg, Addr::Peer, TestRequest::Ping(nonce));
+            assert!(matches!(res, Err(Error::Send(..))));
+        }
+        let pending = controller.pending_sends().map(|p| p.data.clone()).collect::<Vec<_>>();
+        let expected = [TestRequest::Ping(2), TestRequest::Ping(3)].map(|req| req.serialize());
+        assert_eq!(pending, expected);
+        assert_eq!(controller.retry_pending(), 0);
+        assert_eq!(controller.pending_sends().count(), 2);
+
+        let socket = ZMQ_CONTEXT.socket(zmq::ROUTER).unwrap();
+        socket.set_identity(&Vec::from(Addr::Peer)).unwrap();
+        socket.set_rcvtimeo(1000).unwrap();
+        socket.connect(endpoint).unwrap();
+        let mut peer = LocalSession::with_zmq_socket(ZmqSocketType::RouterConnect, socket);
+        let mut sent = 0;
+        for _ in 0..100 {
+            sent = controller.retry_pending();
+            if sent > 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(sent, 2);
+        assert_eq!(controller.pending_sends().count(), 0);
+        for nonce in 2..=3 {
+            let frame = peer.recv_routed_message().unwrap();
+            assert_eq!(frame.msg, TestRequest::Ping(nonce).serialize());
+        }
+    }
+}
+
@@ -0,0 +1,75 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+/// Filter detecting repeated messages by the hash of their source, destination
+/// and content among a fixed number of the recently seen messages, optionally
+/// forgetting messages seen more than `ttl` ago.
+pub(super) struct DedupFilter {
+    window: usize,
+    ttl: Option<Duration>,
+    seen: HashSet<u64>,
+    order: VecDeque<(u64, Instant)>,
+}
+
+impl DedupFilter {
+    pub(super) fn with_window(window: usize, ttl: Option<Duration>) -> Self {
+        Self {
+            window,
+            ttl,
+            seen: HashSet::with_capacity(window),
+            order: VecDeque::with_capacity(window),
+        }
+    }
+
+    /// Remembers the message received at `now`, returning whether it was
+    /// already seen within the window
+    pub(super) fn is_duplicate(
+        &mut self,
+        source: &impl Hash,
+        dest: &impl Hash,
+        msg: &[u8],
+        now: Instant,
+    ) -> bool {
+        let mut hasher = DefaultHasher::new();
+        source.hash(&mut hasher);
+        dest.hash(&mut hasher);
+        msg.hash(&mut hasher);
+        let digest = hasher.finish();
+
+        if let Some(ttl) = self.ttl {
+            while let Some(&(oldest, seen_at)) = self.order.front() {
+                if now.saturating_duration_since(seen_at) < ttl {
+                    break;
+                }
+                self.order.pop_front();
+                self.seen.remove(&oldest);
+            }
+        }
+        if self.seen.contains(&digest) {
+            return true;
+        }
+        if self.order.len() >= self.window {
+            if let Some((oldest, _)) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(digest);
+        self.order.push_back((digest, now));
+        false
+    }
+}
@@ -13,14 +13,26 @@
 
 mod bus;
 mod controller;
+mod dedup;
+mod watchdog;
 
-pub use bus::{BusConfig, BusId, ClientId, ServiceAddress, ServiceName};
-pub use controller::{Controller, EndpointList, Handler, PollItem};
+pub use bus::{
+    BusConfig, BusId, ClientId, ServiceAddress, ServiceName, StandardServiceAddress,
+    ESB_FRAME_VERSION,
+};
+pub use controller::{
+    Controller, ControllerConfig, DeadLetterSink, EndpointList, Handler, MultipartItem,
+    PendingSend, PollItem, RequestFilter,
+};
+pub use watchdog::{HandlerStats, StuckHandlerCallback};
 use internet2::{presentation, transport};
 
+use crate::rpc::ErrorKind;
+
 /// Errors happening with RPC APIs
 #[derive(Clone, Debug, Display, Error, From)]
 #[display(doc_comments)]
+#[non_exhaustive]
 pub enum Error<A: ServiceAddress> {
     /// unexpected server response
     UnexpectedServerResponse,
@@ -41,6 +53,23 @@ pub enum Error<A: ServiceAddress> {
 
     /// {0}
     ServiceError(String),
+
+    /// peer {0} uses ESB frame version {1}, which is not supported
+    IncompatibleProtocolVersion(A, u8),
+}
+
+impl<A: ServiceAddress> Error<A> {
+    /// Classifies the error
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::UnexpectedServerResponse => ErrorKind::Protocol,
+            Error::Presentation(err) => err.into(),
+            Error::Send(_, _, err) | Error::Transport(err) => err.into(),
+            Error::UnknownBusId(_) => ErrorKind::Configuration,
+            Error::ServiceError(_) => ErrorKind::Remote,
+            Error::IncompatibleProtocolVersion(..) => ErrorKind::Unsupported,
+        }
+    }
 }
 
 impl<A: ServiceAddress> From<zmq::Error> for Error<A> {
@@ -55,3 +84,137 @@ impl<A: ServiceAddress> From<presentation::Error> for Error<A> {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    //! Service bus, handler and in-process sockets shared by the ESB tests
+
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use internet2::session::LocalSession;
+    use internet2::{SendRecvMessage, ZmqSocketType};
+
+    use super::{BusId, EndpointList, Error, Handler, StandardServiceAddress};
+    use crate::rpc::test::TestRequest;
+    use crate::ZMQ_CONTEXT;
+
+    pub type Addr = StandardServiceAddress;
+
+    #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+    #[display(Debug)]
+    pub enum TestBus {
+        Msg,
+        Ctl,
+    }
+
+    impl BusId for TestBus {
+        type Address = Addr;
+    }
+
+    /// Callback invoked by [`Recorder`] for each of the handled requests
+    pub type Hook = Box<dyn FnMut(&mut EndpointList<TestBus>, TestBus, &TestRequest) + Send>;
+
+    /// Handler recording the handled requests and errors
+    pub struct Recorder {
+        pub identity: Addr,
+        pub handled: Arc<Mutex<Vec<(TestBus, Addr, TestRequest)>>>,
+        pub errors: Arc<Mutex<Vec<String>>>,
+        pub hook: Option<Hook>,
+    }
+
+    impl Recorder {
+        pub fn new(identity: Addr) -> Self {
+            Recorder { identity, handled: none!(), errors: none!(), hook: None }
+        }
+
+        /// Constructs recorder calling `hook` before recording each request
+        pub fn with_hook(
+            identity: Addr,
+            hook: impl FnMut(&mut EndpointList<TestBus>, TestBus, &TestRequest) + Send + 'static,
+        ) -> Self {
+            Recorder { hook: Some(Box::new(hook)), ..Recorder::new(identity) }
+        }
+
+        /// Returns requests handled so far
+        pub fn requests(handled: &Mutex<Vec<(TestBus, Addr, TestRequest)>>) -> Vec<TestRequest> {
+            handled.lock().unwrap().iter().map(|(_, _, request)| request.clone()).collect()
+        }
+    }
+
+    impl Handler<TestBus> for Recorder {
+        type Request = TestRequest;
+        type Error = Error<Addr>;
+
+        fn identity(&self) -> Addr { self.identity.clone() }
+
+        fn handle(
+            &mut self,
+            endpoints: &mut EndpointList<TestBus>,
+            bus_id: TestBus,
+            source: Addr,
+            request: TestRequest,
+        ) -> Result<(), Self::Error> {
+            if let Some(ref mut hook) = self.hook {
+                hook(endpoints, bus_id, &request);
+            }
+            self.handled.lock().unwrap().push((bus_id, source, request));
+            Ok(())
+        }
+
+        fn handle_err(
+            &mut self,
+            _endpoints: &mut EndpointList<TestBus>,
+            error: Error<Addr>,
+        ) -> Result<(), Self::Error> {
+            self.errors.lock().unwrap().push(error.to_string());
+            Ok(())
+        }
+    }
+
+    /// Creates pair of connected ROUTER sockets over `inproc://{name}`: the
+    /// first one is bound with `bound` identity, the second one is connected
+    /// to it with `connected` identity.
+    pub fn router_sockets(name: &str, bound: Addr, connected: Addr) -> (zmq::Socket, zmq::Socket) {
+        let endpoint = format!("inproc://{}", name);
+        let server = ZMQ_CONTEXT.socket(zmq::ROUTER).unwrap();
+        server.set_identity(&Vec::from(bound)).unwrap();
+        server.set_router_mandatory(true).unwrap();
+        server.bind(&endpoint).unwrap();
+        let client = ZMQ_CONTEXT.socket(zmq::ROUTER).unwrap();
+        client.set_identity(&Vec::from(connected)).unwrap();
+        client.set_router_mandatory(true).unwrap();
+        client.connect(&endpoint).unwrap();
+        (server, client)
+    }
+
+    /// Creates pair of connected ROUTER sessions; see [`router_sockets`]
+    pub fn router_pair(name: &str, bound: Addr, connected: Addr) -> (LocalSession, LocalSession) {
+        let (server, client) = router_sockets(name, bound, connected);
+        (
+            LocalSession::with_zmq_socket(ZmqSocketType::RouterBind, server),
+            LocalSession::with_zmq_socket(ZmqSocketType::RouterConnect, client),
+        )
+    }
+
+    /// Sends routed message, retrying while the connection handshake is not
+    /// completed yet
+    pub fn send_routed(
+        session: &mut LocalSession,
+        source: Addr,
+        route: Addr,
+        dest: Addr,
+        msg: &[u8],
+    ) {
+        let (source, route, dest): (Vec<u8>, Vec<u8>, Vec<u8>) =
+            (source.into(), route.into(), dest.into());
+        for _ in 0..100 {
+            if session.send_routed_message(&source, &route, &dest, msg).is_ok() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("unable to send routed message");
+    }
+}
@@ -0,0 +1,100 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::util::Clock;
+
+/// Callback invoked by the controller watchdog with the time for which the
+/// handler is already blocked; may be used to abort a stuck service.
+pub type StuckHandlerCallback = Box<dyn Fn(Duration) + Send>;
+
+/// Durations of [`Handler::handle`] calls made by the controller
+///
+/// [`Handler::handle`]: crate::esb::Handler::handle
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct HandlerStats {
+    /// Number of completed calls
+    pub calls: u64,
+
+    /// Duration of the last completed call
+    pub last: Duration,
+
+    /// Maximal duration of a call
+    pub max: Duration,
+
+    /// Total time spent in the handler
+    pub total: Duration,
+}
+
+impl HandlerStats {
+    pub(super) fn record(&mut self, duration: Duration) {
+        self.calls += 1;
+        self.last = duration;
+        self.max = self.max.max(duration);
+        self.total += duration;
+    }
+}
+
+/// Thread watching for the handler calls exceeding timeout, measured with the
+/// provided clock. The thread is stopped once the watchdog is dropped.
+pub(super) struct Watchdog {
+    busy_since: Arc<Mutex<Option<Instant>>>,
+    _stop: mpsc::Sender<()>,
+}
+
+impl Watchdog {
+    pub(super) fn spawn(
+        timeout: Duration,
+        on_stuck: Option<StuckHandlerCallback>,
+        clock: Arc<dyn Clock + Send + Sync>,
+    ) -> Result<Self, io::Error> {
+        let busy_since = Arc::new(Mutex::new(None::<Instant>));
+        let (stop, stopped) = mpsc::channel::<()>();
+        let state = busy_since.clone();
+        // Checking at least every 100 ms lets the watchdog notice the timeout
+        // in time even if the clock is advanced faster than the real time
+        let period = (timeout / 4).clamp(Duration::from_millis(1), Duration::from_millis(100));
+        thread::Builder::new().name(s!("esb-watchdog")).spawn(move || {
+            let mut reported = None;
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(period) {
+                let since = *state.lock().expect("ESB watchdog state is poisoned");
+                let since = match since {
+                    Some(since) if reported != Some(since) => since,
+                    _ => continue,
+                };
+                let blocked = clock.now().saturating_duration_since(since);
+                if blocked > timeout {
+                    reported = Some(since);
+                    warn!("ESB handler is blocked for {:?}", blocked);
+                    if let Some(ref on_stuck) = on_stuck {
+                        on_stuck(blocked);
+                    }
+                }
+            }
+        })?;
+        Ok(Self { busy_since, _stop: stop })
+    }
+
+    pub(super) fn enter(&self, since: Instant) { self.set(Some(since)) }
+
+    pub(super) fn leave(&self) { self.set(None) }
+
+    fn set(&self, since: Option<Instant>) {
+        *self.busy_since.lock().expect("ESB watchdog state is poisoned") = since;
+    }
+}
@@ -11,8 +11,12 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+use std::io::{self, Write};
+use std::path::Path;
 use std::str::FromStr;
 
+use serde::Serialize;
+
 #[derive(Clone, PartialEq, Eq, Hash, Debug, Display, Error, From)]
 #[display(doc_comments)]
 /// Unknown format string
@@ -201,6 +205,19 @@ impl FileFormat {
             FileFormat::StrictEncode => "se",
         }
     }
+
+    /// Detects file format from the extension of the file `path`. Returns
+    /// `None` if the file has no extension or the extension is not known.
+    pub fn from_extension(path: impl AsRef<Path>) -> Option<Self> {
+        let ext = path.as_ref().extension()?.to_str()?.to_lowercase();
+        Some(match ext.as_str() {
+            "json" => FileFormat::Json,
+            "yaml" | "yml" => FileFormat::Yaml,
+            "toml" => FileFormat::Toml,
+            "se" => FileFormat::StrictEncode,
+            _ => return None,
+        })
+    }
 }
 
 impl FromStr for FileFormat {
@@ -223,3 +240,120 @@ impl FromStr for FileFormat {
         })
     }
 }
+
+/// Writes `items` to `writer` one by one as a JSON array or YAML sequence,
+/// such that large collections are output without buffering all of their
+/// serialized data in memory. Returns the number of the written items.
+///
+/// # Errors
+///
+/// Fails with [`io::ErrorKind::Unsupported`] for the formats which can't
+/// represent a top-level sequence ([`FileFormat::Toml`] and
+/// [`FileFormat::StrictEncode`]), as well as on serialization and I/O errors.
+pub fn write_stream<T, W>(
+    format: FileFormat,
+    items: impl IntoIterator<Item = T>,
+    writer: &mut W,
+) -> io::Result<usize>
+where
+    T: Serialize,
+    W: Write,
+{
+    let mut count = 0usize;
+    match format {
+        FileFormat::Json => {
+            writer.write_all(b"[")?;
+            for item in items {
+                writer.write_all(if count == 0 { b"\n" } else { b",\n" })?;
+                serde_json::to_writer(&mut *writer, &item)?;
+                count += 1;
+            }
+            writer.write_all(if count == 0 { b"]\n" } else { b"\n]\n" })?;
+        }
+        FileFormat::Yaml => {
+            for item in items {
+                // Single-item sequences concatenate into a single sequence
+                serde_yaml::to_writer(&mut *writer, &[item])
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                count += 1;
+            }
+            if count == 0 {
+                writer.write_all(b"[]\n")?;
+            }
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("streaming output is not supported for {} format", format),
+            ))
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[test]
+    fn from_extension() {
+        for (path, format) in [
+            ("out.json", Some(FileFormat::Json)),
+            ("out.yaml", Some(FileFormat::Yaml)),
+            ("out.yml", Some(FileFormat::Yaml)),
+            ("out.toml", Some(FileFormat::Toml)),
+            ("out.se", Some(FileFormat::StrictEncode)),
+            ("dir/OUT.JSON", Some(FileFormat::Json)),
+            ("out.cbor", None),
+            ("out", None),
+            (".json", None),
+        ] {
+            assert_eq!(FileFormat::from_extension(path), format, "{}", path);
+        }
+    }
+
+    #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    #[serde(crate = "serde_crate")]
+    struct Item {
+        id: u32,
+        name: String,
+    }
+
+    fn items() -> impl Iterator<Item = Item> {
+        (0..10_000).map(|id| Item { id, name: format!("item #{}", id) })
+    }
+
+    #[test]
+    fn write_stream_round_trip() {
+        let expected = items().collect::<Vec<_>>();
+        for format in [FileFormat::Json, FileFormat::Yaml] {
+            let mut buf = vec![];
+            assert_eq!(write_stream(format, items(), &mut buf).unwrap(), 10_000);
+            let parsed: Vec<Item> = match format {
+                FileFormat::Json => serde_json::from_slice(&buf).unwrap(),
+                _ => serde_yaml::from_slice(&buf).unwrap(),
+            };
+            assert_eq!(parsed, expected, "{}", format);
+
+            let mut buf = vec![];
+            assert_eq!(write_stream(format, Vec::<Item>::new(), &mut buf).unwrap(), 0);
+            let parsed: Vec<Item> = match format {
+                FileFormat::Json => serde_json::from_slice(&buf).unwrap(),
+                _ => serde_yaml::from_slice(&buf).unwrap(),
+            };
+            assert!(parsed.is_empty(), "{}", format);
+        }
+    }
+
+    #[test]
+    fn write_stream_unsupported() {
+        for format in [FileFormat::Toml, FileFormat::StrictEncode] {
+            let mut buf = vec![];
+            let err = write_stream(format, items(), &mut buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::Unsupported, "{}", format);
+            assert!(buf.is_empty());
+        }
+    }
+}
@@ -41,13 +41,18 @@ extern crate serde_with;
 #[cfg(feature = "clap")]
 #[macro_use]
 extern crate clap;
-#[cfg_attr(feature = "_rpc", macro_use)]
+#[cfg_attr(all(any(feature = "_rpc", feature = "quic"), not(feature = "tracing")), macro_use)]
 extern crate log;
+#[cfg(feature = "tracing")]
+#[cfg_attr(any(feature = "_rpc", feature = "quic"), macro_use)]
+extern crate tracing;
 #[cfg(feature = "zmq")]
 extern crate zmq2 as zmq;
 
 #[cfg(feature = "cli")]
 pub mod cli;
+#[cfg(feature = "quic")]
+pub mod datagram;
 pub mod error;
 #[cfg(feature = "_rpc")]
 pub mod esb;
@@ -69,7 +74,7 @@ pub mod util;
 use once_cell::sync::Lazy;
 
 #[cfg(feature = "shell")]
-pub use crate::format::{BinaryFormat, FileFormat, FormatParseError, StructuredFormat};
+pub use crate::format::{write_stream, BinaryFormat, FileFormat, FormatParseError, StructuredFormat};
 #[cfg(feature = "node")]
 pub use crate::launcher::{DaemonHandle, Launcher, LauncherError};
 
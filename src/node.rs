@@ -12,6 +12,17 @@
 // If not, see <https://opensource.org/licenses/MIT>.
 
 use std::error::Error;
+use std::io;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use internet2::addr::{InetSocketAddr, InetSocketAddrExt, Transport};
+use internet2::Api;
+
+use crate::esb::{self, BusId, EndpointList, ServiceAddress};
+use crate::rpc;
+use crate::util::{Clock, SystemClock};
 
 /// Trait for simpler service implementation with run loops
 pub trait Service {
@@ -46,3 +57,530 @@ pub trait TryService: Sized {
     /// [`TryService::ErrorType`] or never return.
     fn try_run_loop(self) -> Result<(), Self::ErrorType>;
 }
+
+/// Service which run loop consists of repeated iterations, which can be driven
+/// and observed from outside, for instance by [`Timed`] wrapper
+pub trait TryServiceStep: TryService {
+    /// Prepares service for running; called once before the first iteration
+    fn try_prepare(&mut self) -> Result<(), Self::ErrorType> { Ok(()) }
+
+    /// Runs single iteration of the service run loop, blocking until some
+    /// messages are processed, and returns the number of the processed
+    /// messages.
+    fn try_run_step(&mut self) -> Result<usize, Self::ErrorType>;
+}
+
+/// Histogram of durations with power-of-two microsecond buckets
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct LatencyHistogram {
+    /// Number of recorded durations in each bucket; bucket `i` counts the
+    /// durations below `2^(i+1)` and no less than `2^i` microseconds (the
+    /// first bucket also includes durations below 1 microsecond)
+    pub buckets: [u64; 32],
+
+    /// Number of recorded durations
+    pub count: u64,
+
+    /// Maximal recorded duration
+    pub max: Duration,
+
+    /// Sum of all recorded durations
+    pub total: Duration,
+}
+
+impl LatencyHistogram {
+    /// Records `duration` into the histogram
+    pub fn record(&mut self, duration: Duration) {
+        let micros = duration.as_micros().max(1);
+        let bucket = (127 - micros.leading_zeros() as usize).min(self.buckets.len() - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.max = self.max.max(duration);
+        self.total += duration;
+    }
+
+    /// Returns average of the recorded durations, if any
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(self.total / self.count.min(u32::MAX as u64) as u32)
+    }
+
+    /// Returns upper bound of the bucket containing `quantile` (from 0 to 1)
+    /// of the recorded durations, if any
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((self.count as f64 * quantile.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut seen = 0u64;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(Duration::from_micros(1u64 << (bucket + 1)).min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+}
+
+/// Timings of the service run loop collected by [`Timed`] wrapper
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct ServiceTimings {
+    /// Durations of the run loop iterations
+    pub iterations: LatencyHistogram,
+
+    /// Durations of the message processing. Since the messages processed in
+    /// a single iteration are not timed separately, each of them is recorded
+    /// with the average duration of the iteration processing.
+    pub messages: LatencyHistogram,
+}
+
+/// Wrapper around a service, recording timings of its run loop iterations and
+/// message processing.
+///
+/// Since the run loop consumes the service, the timings are accessed via a
+/// shared handle, which should be obtained with [`Timed::timings`] before
+/// running the service. The durations are measured with the [`Clock`]
+/// provided to the wrapper, which is the system clock by default.
+pub struct Timed<S, C = SystemClock>
+where
+    S: TryServiceStep,
+    C: Clock,
+{
+    service: S,
+    timings: Arc<Mutex<ServiceTimings>>,
+    clock: C,
+}
+
+impl<S> Timed<S>
+where
+    S: TryServiceStep,
+{
+    /// Wraps the service
+    pub fn new(service: S) -> Self { Self::with_clock(service, SystemClock) }
+}
+
+impl<S, C> Timed<S, C>
+where
+    S: TryServiceStep,
+    C: Clock,
+{
+    /// Wraps the service in the same way as [`Timed::new`], using `clock` to
+    /// measure the durations.
+    pub fn with_clock(service: S, clock: C) -> Self {
+        Self { service, timings: none!(), clock }
+    }
+
+    /// Returns shared handle to the collected timings
+    pub fn timings(&self) -> Arc<Mutex<ServiceTimings>> { self.timings.clone() }
+
+    /// Returns reference to the wrapped service
+    pub fn inner(&self) -> &S { &self.service }
+
+    /// Returns the wrapped service
+    pub fn into_inner(self) -> S { self.service }
+}
+
+impl<S, C> TryService for Timed<S, C>
+where
+    S: TryServiceStep,
+    C: Clock,
+{
+    type ErrorType = S::ErrorType;
+
+    fn try_run_loop(mut self) -> Result<(), Self::ErrorType> {
+        self.service.try_prepare()?;
+        loop {
+            let start = self.clock.now();
+            let count = self.service.try_run_step()?;
+            let elapsed = self.clock.now().saturating_duration_since(start);
+            let mut timings = self.timings.lock().expect("timings lock is poisoned");
+            timings.iterations.record(elapsed);
+            if count > 0 {
+                let per_message = elapsed / count.min(u32::MAX as usize) as u32;
+                for _ in 0..count {
+                    timings.messages.record(per_message);
+                }
+            }
+        }
+    }
+}
+
+/// Local socket accepting connections or datagrams, created with [`bind`]
+#[derive(Debug, From)]
+pub enum Listener {
+    /// TCP listener
+    #[from]
+    Tcp(TcpListener),
+
+    /// UDP socket receiving datagrams (used for both `udp://` and `quic://`
+    /// transports)
+    #[from]
+    Datagram(UdpSocket),
+}
+
+impl Listener {
+    /// Returns the socket address the listener is bound to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr(),
+            Listener::Datagram(socket) => socket.local_addr(),
+        }
+    }
+}
+
+/// Binds local listener for the transport and IP socket address given by
+/// `ext`. This is the server-side counterpart of the peer dialing functions.
+///
+/// # Errors
+///
+/// Fails with [`io::ErrorKind::InvalidInput`] for onion addresses, which can't
+/// be bound locally, and with [`io::ErrorKind::Unsupported`] for transports
+/// other than TCP, UDP and QUIC (like multipath TCP).
+pub fn bind(ext: &InetSocketAddrExt) -> io::Result<Listener> {
+    let InetSocketAddrExt(transport, addr) = ext;
+    let addr = match *addr {
+        InetSocketAddr::IPv4(socket) => SocketAddr::V4(socket),
+        InetSocketAddr::IPv6(socket) => SocketAddr::V6(socket),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "onion addresses can't be bound locally",
+            ))
+        }
+    };
+    debug!("Binding {} listener on {}", transport, addr);
+    match transport {
+        Transport::Tcp => TcpListener::bind(addr).map(Listener::Tcp),
+        Transport::Udp | Transport::Quic => UdpSocket::bind(addr).map(Listener::Datagram),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} listeners are not supported", transport),
+        )),
+    }
+}
+
+/// Requests supported by [`StatusService`]
+#[derive(Clone, Debug, Display, Api)]
+#[api(encoding = "strict")]
+#[display(inner)]
+pub enum StatusRequest {
+    /// Requests current node status
+    #[api(type = 0xFF00)]
+    #[display("status()")]
+    Status,
+}
+
+impl rpc::Request for StatusRequest {}
+
+/// Replies produced by [`StatusService`]
+#[derive(Clone, Debug, Display, Api)]
+#[api(encoding = "strict")]
+#[display(inner)]
+pub enum StatusReply {
+    /// Current node status
+    #[api(type = 0xFF01)]
+    #[display("status({0})")]
+    Status(NodeStatus),
+}
+
+impl rpc::Request for StatusReply {}
+impl rpc::Reply for StatusReply {}
+
+/// Health information reported by a node
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display, StrictEncode, StrictDecode)]
+#[display("{name} v{version}, uptime {uptime}s")]
+pub struct NodeStatus {
+    /// Name of the node
+    pub name: String,
+
+    /// Version of the node software
+    pub version: String,
+
+    /// Number of seconds passed since the node start
+    pub uptime: u64,
+
+    /// Service buses the node is connected to
+    pub buses: Vec<String>,
+}
+
+/// Reusable ESB handler answering [`StatusRequest`]s, which standardizes
+/// health reporting across nodes.
+///
+/// The service may be run by a dedicated controller on its own service bus;
+/// alternatively nodes may call [`StatusService::reply`] from their own
+/// handlers.
+pub struct StatusService<A, C = SystemClock>
+where
+    A: ServiceAddress,
+    C: Clock,
+{
+    identity: A,
+    name: String,
+    version: String,
+    started: Instant,
+    clock: C,
+}
+
+impl<A> StatusService<A>
+where
+    A: ServiceAddress,
+{
+    /// Constructs status service for the node with a given name and version;
+    /// the node uptime is counted starting from this moment
+    pub fn new(identity: A, name: impl ToString, version: impl ToString) -> Self {
+        Self::with_clock(identity, name, version, SystemClock)
+    }
+}
+
+impl<A, C> StatusService<A, C>
+where
+    A: ServiceAddress,
+    C: Clock,
+{
+    /// Constructs status service in the same way as [`StatusService::new`],
+    /// counting the node uptime with `clock`
+    pub fn with_clock(
+        identity: A,
+        name: impl ToString,
+        version: impl ToString,
+        clock: C,
+    ) -> Self {
+        Self {
+            identity,
+            name: name.to_string(),
+            version: version.to_string(),
+            started: clock.now(),
+            clock,
+        }
+    }
+
+    /// Composes current node status
+    pub fn status<B>(&self, endpoints: &EndpointList<B>) -> NodeStatus
+    where
+        B: BusId<Address = A>,
+    {
+        NodeStatus {
+            name: self.name.clone(),
+            version: self.version.clone(),
+            uptime: self.clock.now().saturating_duration_since(self.started).as_secs(),
+            buses: endpoints.bus_ids().map(|bus_id| bus_id.to_string()).collect(),
+        }
+    }
+
+    /// Sends current node status to `dest` via `bus_id` service bus
+    pub fn reply<B>(
+        &self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        dest: A,
+    ) -> Result<(), esb::Error<A>>
+    where
+        B: BusId<Address = A>,
+    {
+        let reply = StatusReply::Status(self.status(endpoints));
+        endpoints.send_to(bus_id, self.identity.clone(), dest, reply)
+    }
+}
+
+impl<B, C> esb::Handler<B> for StatusService<B::Address, C>
+where
+    B: BusId,
+    C: Clock,
+{
+    type Request = StatusRequest;
+    type Error = esb::Error<B::Address>;
+
+    fn identity(&self) -> B::Address { self.identity.clone() }
+
+    fn handle(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        source: B::Address,
+        request: StatusRequest,
+    ) -> Result<(), Self::Error> {
+        match request {
+            StatusRequest::Status => self.reply(endpoints, bus_id, source),
+        }
+    }
+
+    fn handle_err(
+        &mut self,
+        _endpoints: &mut EndpointList<B>,
+        error: esb::Error<B::Address>,
+    ) -> Result<(), Self::Error> {
+        // Status requests are best-effort; failures must not stop the service
+        warn!("Status service error: {}", error);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, SocketAddrV4, TcpStream};
+
+    use internet2::{CreateUnmarshaller, SendRecvMessage, TypedEnum, Unmarshall};
+
+    use super::*;
+    use crate::esb::test::{router_pair, send_routed, Addr, TestBus};
+    use crate::esb::Controller;
+    use crate::util::MockClock;
+
+    #[test]
+    fn status_over_esb() {
+        let clock = MockClock::new();
+        let service = StatusService::with_clock(Addr::Daemon, "testd", "1.2.3", clock.clone());
+        let (session, mut peer) = router_pair("status", Addr::Daemon, Addr::Peer);
+        let mut controller: Controller<TestBus, StatusRequest, _> =
+            Controller::from_sessions(map! { TestBus::Msg => session }, None, service);
+
+        clock.advance(Duration::from_secs(90));
+        let msg = StatusRequest::Status.serialize();
+        send_routed(&mut peer, Addr::Peer, Addr::Daemon, Addr::Daemon, &msg);
+        assert_eq!(controller.run_n(1).unwrap(), 1);
+
+        let frame = peer.recv_routed_message().unwrap();
+        assert_eq!(Addr::from(frame.src), Addr::Daemon);
+        let reply = StatusReply::create_unmarshaller().unmarshall(&frame.msg[..]).unwrap();
+        let StatusReply::Status(status) = (*reply).clone();
+        assert_eq!(status, NodeStatus {
+            name: s!("testd"),
+            version: s!("1.2.3"),
+            uptime: 90,
+            buses: vec![s!("Msg")],
+        });
+    }
+
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.mean(), None);
+        assert_eq!(histogram.quantile(0.5), None);
+
+        for (micros, bucket) in [(0, 0), (1, 0), (2, 1), (3, 1), (4, 2), (1023, 9), (1024, 10)] {
+            let mut single = LatencyHistogram::default();
+            single.record(Duration::from_micros(micros));
+            assert_eq!(single.buckets[bucket], 1, "{} us", micros);
+            assert_eq!(single.buckets.iter().sum::<u64>(), 1);
+        }
+        let mut single = LatencyHistogram::default();
+        single.record(Duration::from_secs(1_000_000));
+        assert_eq!(single.buckets[31], 1);
+
+        for micros in [1, 2, 3, 100] {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.count, 4);
+        assert_eq!(&histogram.buckets[..8], &[1, 2, 0, 0, 0, 0, 1, 0]);
+        assert_eq!(histogram.max, Duration::from_micros(100));
+        assert_eq!(histogram.total, Duration::from_micros(106));
+        assert_eq!(histogram.mean(), Some(Duration::from_nanos(26_500)));
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_micros(4)));
+        assert_eq!(histogram.quantile(1.0), Some(Duration::from_micros(100)));
+    }
+
+    #[derive(Debug, Display, Error)]
+    #[display(doc_comments)]
+    /// no more steps
+    struct Stop;
+
+    /// Service running predefined steps, each taking the given time on the
+    /// mock clock and processing the given number of messages (starting from
+    /// the last one), and failing once they are over
+    struct Steps {
+        clock: MockClock,
+        steps: Vec<(Duration, usize)>,
+    }
+
+    impl TryService for Steps {
+        type ErrorType = Stop;
+
+        fn try_run_loop(mut self) -> Result<(), Stop> {
+            loop {
+                self.try_run_step()?;
+            }
+        }
+    }
+
+    impl TryServiceStep for Steps {
+        fn try_run_step(&mut self) -> Result<usize, Stop> {
+            let (duration, count) = self.steps.pop().ok_or(Stop)?;
+            self.clock.advance(duration);
+            Ok(count)
+        }
+    }
+
+    #[test]
+    fn timed_service() {
+        let clock = MockClock::new();
+        let ms = Duration::from_millis;
+        let steps = vec![(ms(10), 1), (Duration::from_micros(500), 0), (ms(3), 3)];
+        let service = Timed::with_clock(Steps { clock: clock.clone(), steps }, clock);
+        let timings = service.timings();
+        assert!(service.try_run_loop().is_err());
+
+        let timings = *timings.lock().unwrap();
+        let iterations = timings.iterations;
+        assert_eq!(iterations.count, 3);
+        let buckets = iterations.buckets;
+        assert_eq!((buckets[8], buckets[11], buckets[13]), (1, 1, 1));
+        assert_eq!(iterations.max, ms(10));
+        assert_eq!(iterations.total, Duration::from_micros(13_500));
+        // Messages of the same iteration share its duration
+        let messages = timings.messages;
+        assert_eq!(messages.count, 4);
+        assert_eq!((messages.buckets[9], messages.buckets[13]), (3, 1));
+        assert_eq!(messages.total, ms(13));
+    }
+
+    fn localhost(transport: Transport) -> InetSocketAddrExt {
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
+        InetSocketAddrExt(transport, InetSocketAddr::IPv4(addr))
+    }
+
+    #[test]
+    fn bind_tcp() {
+        let listener = match bind(&localhost(Transport::Tcp)).unwrap() {
+            Listener::Tcp(listener) => listener,
+            listener => panic!("unexpected listener {:?}", listener),
+        };
+        let addr = listener.local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        let (mut conn, _) = listener.accept().unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        conn.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[test]
+    fn bind_datagram() {
+        for transport in [Transport::Udp, Transport::Quic] {
+            let socket = match bind(&localhost(transport)).unwrap() {
+                Listener::Datagram(socket) => socket,
+                listener => panic!("unexpected listener {:?}", listener),
+            };
+            let addr = socket.local_addr().unwrap();
+            assert_ne!(addr.port(), 0);
+
+            let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            client.send_to(b"ping", addr).unwrap();
+            let mut buf = [0u8; 8];
+            let (len, from) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"ping");
+            assert_eq!(from, client.local_addr().unwrap());
+        }
+    }
+
+    #[test]
+    fn bind_unsupported_transport() {
+        let err = bind(&localhost(Transport::Mtcp)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,215 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Per-address exponential backoff of the connection attempts, preventing
+//! reconnect storms to the unavailable peers.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use internet2::addr::InetAddr;
+
+use crate::util::{Clock, SystemClock};
+
+/// Default delay before the next connection attempt after the first failure
+pub const BACKOFF_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Default maximum delay between the connection attempts
+pub const BACKOFF_MAX_DELAY: Duration = Duration::from_secs(600);
+
+/// Consecutive connection failures for a single address
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Failures {
+    count: u32,
+    next_attempt_at: Instant,
+}
+
+/// Tracks consecutive connection failures per peer address, doubling the
+/// delay before the next allowed connection attempt after each failure, up to
+/// the maximum delay.
+///
+/// The current time is taken from the [`Clock`] provided to the tracker,
+/// which is the system clock by default. Methods with `_at` suffix take the
+/// current time as an argument instead.
+#[derive(Clone, Debug)]
+pub struct AddressBackoff<C = SystemClock>
+where
+    C: Clock,
+{
+    base_delay: Duration,
+    max_delay: Duration,
+    failures: HashMap<InetAddr, Failures>,
+    clock: C,
+}
+
+impl Default for AddressBackoff {
+    fn default() -> Self { Self::new(BACKOFF_BASE_DELAY, BACKOFF_MAX_DELAY) }
+}
+
+impl AddressBackoff {
+    /// Constructs backoff tracker delaying attempts by `base_delay` after the
+    /// first failure and never more than by `max_delay`.
+    pub fn new(base_delay: Duration, max_delay: Duration) -> Self {
+        Self::with_clock(base_delay, max_delay, SystemClock)
+    }
+}
+
+impl<C> AddressBackoff<C>
+where
+    C: Clock,
+{
+    /// Constructs backoff tracker in the same way as [`AddressBackoff::new`],
+    /// using `clock` as the source of the current time.
+    pub fn with_clock(base_delay: Duration, max_delay: Duration, clock: C) -> Self {
+        Self { base_delay, max_delay, failures: none!(), clock }
+    }
+
+    /// Records failed connection attempt to `addr` happened now, returning the
+    /// delay before the next allowed attempt.
+    pub fn record_failure(&mut self, addr: InetAddr) -> Duration {
+        self.record_failure_at(addr, self.clock.now())
+    }
+
+    /// Records failed connection attempt to `addr` happened at `now`,
+    /// returning the delay before the next allowed attempt.
+    pub fn record_failure_at(&mut self, addr: InetAddr, now: Instant) -> Duration {
+        let count = self.failures.get(&addr).map(|failures| failures.count).unwrap_or_default();
+        let count = count.saturating_add(1);
+        let delay = self
+            .base_delay
+            .checked_mul(1u32.checked_shl(count - 1).unwrap_or(u32::MAX))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        trace!("Connection to {} failed {} times; backing off for {:?}", addr, count, delay);
+        self.failures.insert(addr, Failures { count, next_attempt_at: now + delay });
+        delay
+    }
+
+    /// Records successful connection to `addr`, resetting its backoff.
+    pub fn record_success(&mut self, addr: InetAddr) { self.failures.remove(&addr); }
+
+    /// Returns number of consecutive failures recorded for `addr`.
+    pub fn failure_count(&self, addr: InetAddr) -> u32 {
+        self.failures.get(&addr).map(|failures| failures.count).unwrap_or_default()
+    }
+
+    /// Returns time of the next allowed connection attempt to `addr`, or
+    /// `None` if no failures were recorded for it.
+    pub fn next_attempt_at(&self, addr: InetAddr) -> Option<Instant> {
+        self.failures.get(&addr).map(|failures| failures.next_attempt_at)
+    }
+
+    /// Checks whether connection to `addr` may be attempted now.
+    pub fn is_allowed_now(&self, addr: InetAddr) -> bool {
+        self.is_allowed_at(addr, self.clock.now())
+    }
+
+    /// Checks whether connection to `addr` may be attempted at `now`.
+    pub fn is_allowed_at(&self, addr: InetAddr, now: Instant) -> bool {
+        self.next_attempt_at(addr).map(|at| at <= now).unwrap_or(true)
+    }
+
+    /// Forgets failures of the addresses which have been allowed to be retried
+    /// for at least `keep_for` by `now`, such that the tracker does not grow
+    /// unboundedly. Returns the number of the forgotten addresses.
+    pub fn clear_expired(&mut self, now: Instant, keep_for: Duration) -> usize {
+        let before = self.failures.len();
+        self.failures.retain(|_, failures| failures.next_attempt_at + keep_for > now);
+        before - self.failures.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::MockClock;
+
+    fn backoff() -> (AddressBackoff<MockClock>, MockClock) {
+        let clock = MockClock::new();
+        let (base, max) = (Duration::from_secs(1), Duration::from_secs(10));
+        let backoff = AddressBackoff::with_clock(base, max, clock.clone());
+        (backoff, clock)
+    }
+
+    #[test]
+    fn exponential_growth_up_to_cap() {
+        let (mut backoff, _) = backoff();
+        let addr = InetAddr::from([127, 0, 0, 1]);
+        let delays = (0..6).map(|_| backoff.record_failure(addr)).collect::<Vec<_>>();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10].map(Duration::from_secs));
+        assert_eq!(backoff.failure_count(addr), 6);
+
+        // The delay does not overflow after many failures
+        for _ in 0..100 {
+            backoff.record_failure(addr);
+        }
+        assert_eq!(backoff.record_failure(addr), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let (mut backoff, _) = backoff();
+        let (addr, other) = (InetAddr::from([127, 0, 0, 1]), InetAddr::from([127, 0, 0, 2]));
+        backoff.record_failure(addr);
+        backoff.record_failure(addr);
+        backoff.record_failure(other);
+
+        backoff.record_success(addr);
+        assert_eq!(backoff.failure_count(addr), 0);
+        assert_eq!(backoff.next_attempt_at(addr), None);
+        assert!(backoff.is_allowed_now(addr));
+        assert_eq!(backoff.record_failure(addr), Duration::from_secs(1));
+        assert_eq!(backoff.failure_count(other), 1);
+    }
+
+    #[test]
+    fn attempts_allowed_after_delay() {
+        let (mut backoff, clock) = backoff();
+        let addr = InetAddr::from([127, 0, 0, 1]);
+        let start = clock.now();
+        assert!(backoff.is_allowed_now(addr));
+
+        let delay = backoff.record_failure(addr);
+        let next = backoff.next_attempt_at(addr).unwrap();
+        assert_eq!(next, start + delay);
+        assert!(!backoff.is_allowed_now(addr));
+        assert!(!backoff.is_allowed_at(addr, next - Duration::from_millis(1)));
+        assert!(backoff.is_allowed_at(addr, next));
+
+        clock.advance(delay - Duration::from_millis(1));
+        assert!(!backoff.is_allowed_now(addr));
+        clock.advance(Duration::from_millis(1));
+        assert!(backoff.is_allowed_now(addr));
+    }
+
+    #[test]
+    fn expired_failures_cleared() {
+        let (mut backoff, clock) = backoff();
+        let (addr, other) = (InetAddr::from([127, 0, 0, 1]), InetAddr::from([127, 0, 0, 2]));
+        let keep_for = Duration::from_secs(60);
+        backoff.record_failure(addr);
+        clock.advance(Duration::from_secs(30));
+        backoff.record_failure(other);
+
+        // The first address has been allowed to be retried for 59 seconds
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(backoff.clear_expired(clock.now(), keep_for), 0);
+        assert_eq!(backoff.failure_count(addr), 1);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(backoff.clear_expired(clock.now(), keep_for), 1);
+        assert_eq!(backoff.failure_count(addr), 0);
+        assert_eq!(backoff.next_attempt_at(addr), None);
+        assert_eq!(backoff.failure_count(other), 1);
+    }
+}
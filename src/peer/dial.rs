@@ -0,0 +1,183 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Dual-stack TCP dialing racing connection attempts to the peer endpoints
+//! ("Happy Eyeballs", RFC 8305).
+
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use internet2::addr::InetSocketAddr;
+
+/// Delay after which the next connection attempt is started if the previous
+/// one has not completed yet, as recommended by RFC 8305
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Dials the peer having multiple IPv4 and IPv6 endpoints, returning the
+/// first TCP connection which succeeds within `timeout`.
+///
+/// Connection attempts alternate between IPv6 and IPv4 addresses, starting
+/// with IPv6. Each next attempt is started once the previous one has failed
+/// or [`CONNECTION_ATTEMPT_DELAY`] has passed. Connections which complete
+/// after the winning one are closed. Onion addresses can't be dialed directly
+/// and are skipped.
+pub fn dial_happy_eyeballs(
+    addrs: &[InetSocketAddr],
+    timeout: Duration,
+) -> io::Result<TcpStream> {
+    let (ipv6, ipv4): (Vec<_>, Vec<_>) = addrs
+        .iter()
+        .filter_map(|addr| match addr {
+            InetSocketAddr::IPv4(socket) => Some(SocketAddr::V4(*socket)),
+            InetSocketAddr::IPv6(socket) => Some(SocketAddr::V6(*socket)),
+            _ => None,
+        })
+        .partition(SocketAddr::is_ipv6);
+    let mut queue = Vec::with_capacity(ipv6.len() + ipv4.len());
+    let (mut ipv6, mut ipv4) = (ipv6.into_iter(), ipv4.into_iter());
+    loop {
+        match (ipv6.next(), ipv4.next()) {
+            (None, None) => break,
+            (a, b) => queue.extend(a.into_iter().chain(b)),
+        }
+    }
+    if queue.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no IP addresses to dial"));
+    }
+
+    let deadline = Instant::now() + timeout;
+    let (sender, receiver) = mpsc::channel();
+    let mut attempts = queue.into_iter().peekable();
+    let mut running = 0usize;
+    let mut last_err = io::Error::from(io::ErrorKind::TimedOut);
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+        if let Some(addr) = attempts.next() {
+            debug!("Dialing {}", addr);
+            let sender = sender.clone();
+            thread::spawn(move || {
+                // If the receiver is gone, another connection has already won
+                // and this one is dropped
+                let _ = sender.send(TcpStream::connect_timeout(&addr, remaining));
+            });
+            running += 1;
+        }
+        if running == 0 {
+            return Err(last_err);
+        }
+        let wait = if attempts.peek().is_some() {
+            CONNECTION_ATTEMPT_DELAY.min(remaining)
+        } else {
+            remaining
+        };
+        match receiver.recv_timeout(wait) {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(err)) => {
+                debug!("Connection attempt failed: {}", err);
+                running -= 1;
+                last_err = err;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => unreachable!("sender is held by the function"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv6Addr, SocketAddrV4, SocketAddrV6, TcpListener};
+    use std::os::unix::io::FromRawFd;
+
+    use nix::sys::socket::{bind, listen, socket, AddressFamily, SockFlag, SockType, SockaddrIn6};
+
+    use super::*;
+
+    fn listener_v4() -> (TcpListener, InetSocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = match listener.local_addr().unwrap() {
+            SocketAddr::V4(addr) => InetSocketAddr::IPv4(addr),
+            SocketAddr::V6(_) => unreachable!("bound to IPv4 address"),
+        };
+        (listener, addr)
+    }
+
+    /// IPv4 address on which nobody listens
+    fn closed_v4() -> InetSocketAddr {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        InetSocketAddr::IPv4(SocketAddrV4::new([127, 0, 0, 1].into(), port))
+    }
+
+    /// IPv6 address on which nobody listens
+    fn closed_v6() -> InetSocketAddr {
+        let port = TcpListener::bind("[::1]:0").unwrap().local_addr().unwrap().port();
+        InetSocketAddr::IPv6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, port, 0, 0))
+    }
+
+    /// IPv6 listener whose accept queue is full, so that new connections to it
+    /// hang until they time out
+    fn hanging_v6() -> (TcpListener, TcpStream, InetSocketAddr) {
+        let fd = socket(AddressFamily::Inet6, SockType::Stream, SockFlag::empty(), None).unwrap();
+        let addr = SocketAddrV6::new(Ipv6Addr::LOCALHOST, 0, 0, 0);
+        bind(fd, &SockaddrIn6::from(addr)).unwrap();
+        listen(fd, 0).unwrap();
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        let addr = listener.local_addr().unwrap();
+        // Zero backlog queues a single connection, which is never accepted
+        let queued = TcpStream::connect(addr).unwrap();
+        let addr = match addr {
+            SocketAddr::V6(addr) => InetSocketAddr::IPv6(addr),
+            SocketAddr::V4(_) => unreachable!("bound to IPv6 address"),
+        };
+        (listener, queued, addr)
+    }
+
+    fn is_ipv4(stream: &TcpStream) -> bool { stream.peer_addr().unwrap().is_ipv4() }
+
+    #[test]
+    fn failing_ipv6() {
+        let (_listener, ipv4) = listener_v4();
+        let start = Instant::now();
+        let stream = dial_happy_eyeballs(&[closed_v6(), ipv4], Duration::from_secs(5)).unwrap();
+        assert!(is_ipv4(&stream));
+        // IPv4 attempt starts as soon as IPv6 one fails
+        assert!(start.elapsed() < CONNECTION_ATTEMPT_DELAY);
+    }
+
+    #[test]
+    fn hanging_ipv6() {
+        let (_listener, _queued, ipv6) = hanging_v6();
+        let (_listener, ipv4) = listener_v4();
+        let start = Instant::now();
+        let stream = dial_happy_eyeballs(&[ipv6, ipv4], Duration::from_secs(5)).unwrap();
+        assert!(is_ipv4(&stream));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= CONNECTION_ATTEMPT_DELAY);
+        assert!(elapsed < CONNECTION_ATTEMPT_DELAY + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn all_failing() {
+        let start = Instant::now();
+        let err = dial_happy_eyeballs(&[closed_v6(), closed_v4()], Duration::from_secs(5))
+            .unwrap_err();
+        // The last connection error is returned rather than a timeout
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
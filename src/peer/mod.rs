@@ -11,13 +11,17 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+mod backoff;
 mod connection;
+mod dial;
 pub mod supervisor;
 
 use std::fmt::{Debug, Display};
 use std::net::SocketAddr;
 
+pub use backoff::{AddressBackoff, BACKOFF_BASE_DELAY, BACKOFF_MAX_DELAY};
 pub use connection::{PeerConnection, PeerReceiver, PeerSender, RecvMessage, SendMessage};
+pub use dial::{dial_happy_eyeballs, CONNECTION_ATTEMPT_DELAY};
 use internet2::addr::NodeAddr;
 use internet2::presentation::{Error, TypedEnum, Unmarshall, Unmarshaller};
 
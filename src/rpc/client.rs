@@ -13,6 +13,8 @@
 
 use std::collections::HashMap;
 use std::io::Cursor;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use internet2::addr::ServiceAddr;
 use internet2::session::LocalSession;
@@ -23,17 +25,85 @@ use internet2::{
 
 use super::EndpointId;
 use crate::rpc::connection::Api;
-use crate::rpc::ServerError;
+use crate::rpc::{ErrorKind, FailureCodeExt, Request, ServerError, SocketOptions, TypedRequest};
+use crate::util::{Clock, SystemClock};
 use crate::ZMQ_CONTEXT;
 
+/// Factory constructing sessions which are used by [`RpcClient`] to talk to
+/// the remote endpoints. Allows RPC clients to be backed by transports other
+/// than ZMQ (including in-memory test doubles).
+///
+/// ESB [`Controller`] does not use the factory, since it polls the underlying
+/// ZMQ sockets of its sessions; pre-built sessions may be provided to it with
+/// [`Controller::from_sessions`] instead.
+///
+/// [`Controller`]: crate::esb::Controller
+/// [`Controller::from_sessions`]: crate::esb::Controller::from_sessions
+pub trait SessionFactory: Send {
+    /// Creates new session connected to the remote endpoint at `addr`
+    fn create(
+        &self,
+        addr: &ServiceAddr,
+    ) -> Result<Box<dyn SendRecvMessage + Send>, transport::Error>;
+}
+
+/// Default [`SessionFactory`] connecting to the remote endpoints with ZMQ
+/// `REQ` sockets
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct ZmqSessionFactory {
+    /// Timeout for sending and receiving messages
+    pub timeout: Option<Duration>,
+
+    /// Options applied to each of the created sockets
+    pub socket_options: SocketOptions,
+}
+
+impl ZmqSessionFactory {
+    /// Constructs factory creating sessions which fail sending or receiving
+    /// messages if they do not complete within `timeout`. Timed out requests
+    /// are answered with [`Api::default_reply_on_timeout`], if provided.
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self { timeout: Some(timeout), socket_options: none!() }
+    }
+
+    /// Constructs factory applying `socket_options` to each of the created
+    /// sockets
+    pub fn with_socket_options(socket_options: SocketOptions) -> Self {
+        Self { timeout: None, socket_options }
+    }
+}
+
+impl SessionFactory for ZmqSessionFactory {
+    fn create(
+        &self,
+        addr: &ServiceAddr,
+    ) -> Result<Box<dyn SendRecvMessage + Send>, transport::Error> {
+        // TODO: Replace with RpcSession once its implementation is complete
+        let session = self.socket_options.connect(ZmqSocketType::Req, addr, None)?;
+        if let Some(timeout) = self.timeout {
+            let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+            let socket = session.as_socket();
+            socket.set_sndtimeo(timeout)?;
+            socket.set_rcvtimeo(timeout)?;
+            // Allows sending next request after the previous one has timed
+            // out, discarding late replies to it
+            socket.set_req_relaxed(true)?;
+            socket.set_req_correlate(true)?;
+        }
+        Ok(Box::new(session))
+    }
+}
+
 pub struct RpcClient<E, A>
 where
     A: Api,
     E: EndpointId,
 {
-    // TODO: Replace with RpcSession once its implementation is complete
-    sessions: HashMap<E, LocalSession>,
-    unmarshaller: Unmarshaller<A::Reply>,
+    sessions: HashMap<E, Box<dyn SendRecvMessage + Send>>,
+    endpoints: HashMap<E, ServiceAddr>,
+    factory: Box<dyn SessionFactory>,
+    resolver: Option<Box<dyn Fn(E) -> Option<ServiceAddr> + Send>>,
+    unmarshaller: Arc<Unmarshaller<A::Reply>>,
 }
 
 impl<E, A> RpcClient<E, A>
@@ -41,31 +111,981 @@ where
     A: Api,
     E: EndpointId,
 {
+    /// Constructs RPC client connecting to each of the `endpoints` with ZMQ
+    /// sockets. The client waits for the replies indefinitely, so
+    /// [`Api::default_reply_on_timeout`] is never used by it; use
+    /// [`RpcClient::with_timeout`] for the requests which may time out.
     pub fn with(endpoints: HashMap<E, ServiceAddr>) -> Result<Self, transport::Error> {
-        let mut sessions: HashMap<E, LocalSession> = none!();
-        for (service, endpoint) in endpoints {
-            sessions.insert(
-                service,
-                LocalSession::connect(ZmqSocketType::Req, &endpoint, None, None, &ZMQ_CONTEXT)?,
-            );
+        Self::with_factory(endpoints, ZmqSessionFactory::default())
+    }
+
+    /// Constructs RPC client connecting to each of the `endpoints` with ZMQ
+    /// sockets, failing requests which do not complete within `timeout` or
+    /// answering them with [`Api::default_reply_on_timeout`], if provided.
+    pub fn with_timeout(
+        endpoints: HashMap<E, ServiceAddr>,
+        timeout: Duration,
+    ) -> Result<Self, transport::Error> {
+        Self::with_factory(endpoints, ZmqSessionFactory::with_timeout(timeout))
+    }
+
+    /// Constructs RPC client creating sessions to each of the `endpoints` with
+    /// the provided session `factory`. The factory is kept by the client and
+    /// is used to re-create sessions failed with transport errors.
+    pub fn with_factory(
+        endpoints: HashMap<E, ServiceAddr>,
+        factory: impl SessionFactory + 'static,
+    ) -> Result<Self, transport::Error> {
+        Self::with_unmarshaller(endpoints, factory, Arc::new(A::Reply::create_unmarshaller()))
+    }
+
+    /// Constructs RPC client re-using existing `unmarshaller`, which may be
+    /// shared between multiple clients to avoid re-creating it for each of
+    /// them.
+    pub fn with_unmarshaller(
+        endpoints: HashMap<E, ServiceAddr>,
+        factory: impl SessionFactory + 'static,
+        unmarshaller: Arc<Unmarshaller<A::Reply>>,
+    ) -> Result<Self, transport::Error> {
+        let mut sessions: HashMap<E, Box<dyn SendRecvMessage + Send>> = none!();
+        for (service, endpoint) in &endpoints {
+            sessions.insert(*service, factory.create(endpoint)?);
         }
-        let unmarshaller = A::Reply::create_unmarshaller();
-        Ok(Self { sessions, unmarshaller })
+        Ok(Self { sessions, endpoints, factory: Box::new(factory), resolver: None, unmarshaller })
+    }
+
+    /// Connects to a new endpoint, replacing existing session for it, if any
+    pub fn add_endpoint(&mut self, endpoint: E, addr: ServiceAddr) -> Result<(), transport::Error> {
+        let session = self.factory.create(&addr)?;
+        self.endpoints.insert(endpoint, addr);
+        if let Some(session) = self.sessions.insert(endpoint, session) {
+            discard_session(endpoint, session);
+        }
+        Ok(())
+    }
+
+    /// Sets resolver used to look up addresses of the endpoints which were
+    /// not added to the client yet. Requests to such endpoints connect to the
+    /// resolved address instead of failing with
+    /// [`ServerError::UnknownEndpoint`].
+    pub fn set_resolver(&mut self, resolver: impl Fn(E) -> Option<ServiceAddr> + Send + 'static) {
+        self.resolver = Some(Box::new(resolver));
     }
 
+    /// Sends request to the endpoint and waits for the reply. If the request
+    /// can't be sent because of a transport error, the session is re-created
+    /// with the client session factory and the request is sent once again.
+    /// Failures happening after the request was sent are reported as is,
+    /// since the server may have already executed the request.
     pub fn request(
         &mut self,
         endpoint: E,
         request: A::Request,
     ) -> Result<A::Reply, ServerError<A::FailureCodeExt>> {
+        check_endpoint(endpoint, &request)?;
         let data = request.serialize();
-        let session = self
-            .sessions
+        let raw = match self.send(endpoint, &data) {
+            // The request was not sent, so it may be repeated over a new
+            // session. Once it is sent, the server may execute it, so receive
+            // failures are reported as is. Failures to connect to a newly
+            // resolved endpoint are reported as is too, since there is no
+            // session to re-create.
+            Err(ServerError::Transport(err)) if self.sessions.contains_key(&endpoint) => {
+                warn!("RPC session for endpoint {} has failed: {}; reconnecting", endpoint, err);
+                self.reconnect(endpoint)?;
+                self.send(endpoint, &data)
+            }
+            res => res,
+        }
+        .and_then(|_| self.recv(endpoint));
+        let raw = match raw {
+            Err(err) if err.kind() == ErrorKind::TimedOut => {
+                return A::default_reply_on_timeout().ok_or(err)
+            }
+            res => res?,
+        };
+        let reply = (*self.unmarshaller.unmarshall(Cursor::new(raw))?).clone();
+        Ok(reply)
+    }
+
+    /// Sends request expecting a specific reply type, as declared with
+    /// [`TypedRequest`]. Replies of other types fail with
+    /// [`ServerError::UnexpectedServerResponse`].
+    pub fn request_typed<Q>(
+        &mut self,
+        endpoint: E,
+        request: Q,
+    ) -> Result<Q::Reply, ServerError<A::FailureCodeExt>>
+    where
+        Q: TypedRequest<A>,
+    {
+        let reply = self.request(endpoint, request.into())?;
+        debug!("RPC: checking reply {} matches the request", reply);
+        Q::reply_from(reply).ok_or(ServerError::UnexpectedServerResponse)
+    }
+
+    fn send(&mut self, endpoint: E, data: &[u8]) -> Result<(), ServerError<A::FailureCodeExt>> {
+        if !self.sessions.contains_key(&endpoint) {
+            if let Some(addr) = self.resolver.as_ref().and_then(|resolver| resolver(endpoint)) {
+                debug!("RPC endpoint {} is resolved to {}", endpoint, addr);
+                self.add_endpoint(endpoint, addr)?;
+            }
+        }
+        self.session(endpoint)?.send_raw_message(data)?;
+        Ok(())
+    }
+
+    fn recv(&mut self, endpoint: E) -> Result<Vec<u8>, ServerError<A::FailureCodeExt>> {
+        Ok(self.session(endpoint)?.recv_raw_message()?)
+    }
+
+    fn session(
+        &mut self,
+        endpoint: E,
+    ) -> Result<&mut Box<dyn SendRecvMessage + Send>, ServerError<A::FailureCodeExt>> {
+        self.sessions
             .get_mut(&endpoint)
+            .ok_or_else(|| ServerError::UnknownEndpoint(endpoint.to_string()))
+    }
+
+    /// Re-creates session for the endpoint with the client session factory,
+    /// repeating connection handshake (if any).
+    fn reconnect(&mut self, endpoint: E) -> Result<(), ServerError<A::FailureCodeExt>> {
+        let addr = self
+            .endpoints
+            .get(&endpoint)
             .ok_or_else(|| ServerError::UnknownEndpoint(endpoint.to_string()))?;
-        session.send_raw_message(&data)?;
-        let raw = session.recv_raw_message()?;
+        let session = self.factory.create(addr)?;
+        if let Some(session) = self.sessions.insert(endpoint, session) {
+            discard_session(endpoint, session);
+        }
+        Ok(())
+    }
+
+    /// Sends each of the requests to its endpoint, collecting replies or
+    /// failures per endpoint. Unlike calling [`RpcClient::request`] for each of
+    /// the endpoints, a failing endpoint does not prevent the rest of the
+    /// requests from being processed.
+    pub fn request_all(
+        &mut self,
+        requests: HashMap<E, A::Request>,
+    ) -> HashMap<E, Result<A::Reply, ServerError<A::FailureCodeExt>>> {
+        requests
+            .into_iter()
+            .map(|(endpoint, request)| (endpoint, self.request(endpoint, request)))
+            .collect()
+    }
+
+    /// Closes all client sessions, discarding the messages which were not yet
+    /// delivered. Unlike simple drop of the client, which keeps the linger
+    /// configured for the sockets (infinite by default), this can't hang the
+    /// process exit on the messages to unreachable peers.
+    pub fn close(mut self) {
+        for (endpoint, session) in self.sessions.drain() {
+            discard_session(endpoint, session);
+        }
+    }
+}
+
+/// Checks that the request API matches the API served by the endpoint
+fn check_endpoint<Ext>(
+    endpoint: impl EndpointId,
+    request: &impl Request,
+) -> Result<(), ServerError<Ext>>
+where
+    Ext: FailureCodeExt,
+{
+    match (endpoint.api_name(), request.api_name()) {
+        (Some(served), Some(api)) if served != api => {
+            Err(ServerError::WrongEndpointForRequest(endpoint.to_string(), api, served))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn discard_session(endpoint: impl EndpointId, session: Box<dyn SendRecvMessage>) {
+    debug!("Closing RPC session for endpoint {}", endpoint);
+    // ZMQ sockets linger by default, which may hang the process on exit
+    if let Ok(session) = session.into_any().downcast::<LocalSession>() {
+        if let Err(err) = session.as_socket().set_linger(0) {
+            warn!("Unable to reset linger for RPC endpoint {}: {}", endpoint, err);
+        }
+    }
+}
+
+impl<E, A> Drop for RpcClient<E, A>
+where
+    A: Api,
+    E: EndpointId,
+{
+    fn drop(&mut self) {
+        for endpoint in self.sessions.keys() {
+            debug!("Dropping RPC session for endpoint {}", endpoint);
+        }
+    }
+}
+
+/// Identifier of a request sent by [`DealerClient`]
+pub type RequestId = u64;
+
+/// RPC client multiplexing requests over a single ZMQ `DEALER` socket per
+/// endpoint. Unlike [`RpcClient`], it does not wait for a reply before
+/// sending the next request, allowing many requests to be in flight at once;
+/// replies are matched to the requests by their ids.
+///
+/// Works with the existing `REP`-based RPC servers: the request id is put
+/// into the message envelope, which is returned by the server together with
+/// the reply.
+///
+/// If the client has a timeout set, requests whose replies were not received
+/// with [`DealerClient::recv`] within the timeout are abandoned: receiving
+/// their replies fails, and the replies arriving later are discarded. The
+/// timeout is measured with the client clock, which is the system clock unless
+/// replaced with [`DealerClient::set_clock`].
+pub struct DealerClient<E, A>
+where
+    A: Api,
+    E: EndpointId,
+{
+    sockets: HashMap<E, zmq::Socket>,
+    next_id: RequestId,
+    /// Time at which the requests awaiting replies were sent
+    sent: HashMap<RequestId, Instant>,
+    /// Replies which have arrived before they were asked for
+    pending: HashMap<RequestId, Vec<u8>>,
+    timeout: Option<Duration>,
+    clock: Arc<dyn Clock + Send + Sync>,
+    unmarshaller: Arc<Unmarshaller<A::Reply>>,
+}
+
+impl<E, A> DealerClient<E, A>
+where
+    A: Api,
+    E: EndpointId,
+{
+    pub fn with(endpoints: HashMap<E, ServiceAddr>) -> Result<Self, transport::Error> {
+        Self::with_socket_options(endpoints, none!())
+    }
+
+    /// Constructs client abandoning requests which are not replied within
+    /// `timeout`
+    pub fn with_timeout(
+        endpoints: HashMap<E, ServiceAddr>,
+        timeout: Duration,
+    ) -> Result<Self, transport::Error> {
+        let mut client = Self::with(endpoints)?;
+        client.timeout = Some(timeout);
+        Ok(client)
+    }
+
+    /// Constructs client applying `socket_options` to each of the endpoint
+    /// sockets
+    pub fn with_socket_options(
+        endpoints: HashMap<E, ServiceAddr>,
+        socket_options: SocketOptions,
+    ) -> Result<Self, transport::Error> {
+        let mut sockets = HashMap::with_capacity(endpoints.len());
+        for (endpoint, addr) in endpoints {
+            let socket = ZMQ_CONTEXT.socket(zmq::DEALER)?;
+            socket_options.apply(&socket)?;
+            socket.connect(&addr.zmq_connect_string())?;
+            sockets.insert(endpoint, socket);
+        }
+        Ok(Self {
+            sockets,
+            next_id: 0,
+            sent: none!(),
+            pending: none!(),
+            timeout: None,
+            clock: Arc::new(SystemClock),
+            unmarshaller: Arc::new(A::Reply::create_unmarshaller()),
+        })
+    }
+
+    /// Sets timeout after which the requests which are not replied are
+    /// abandoned; `None` makes the client wait for the replies indefinitely
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) { self.timeout = timeout; }
+
+    /// Replaces the clock measuring the request timeouts, which allows to
+    /// drive the timeouts with a mock clock in tests
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock + Send + Sync>) { self.clock = clock; }
+
+    /// Number of the requests which were sent, but whose replies were not yet
+    /// received with [`DealerClient::recv`]
+    pub fn in_flight(&self) -> usize { self.sent.len() }
+
+    /// Sends request to the endpoint without waiting for the reply. The
+    /// returned id must be used to receive the reply with
+    /// [`DealerClient::recv`].
+    pub fn send(
+        &mut self,
+        endpoint: E,
+        request: A::Request,
+    ) -> Result<RequestId, ServerError<A::FailureCodeExt>> {
+        check_endpoint(endpoint, &request)?;
+        let socket = self
+            .sockets
+            .get(&endpoint)
+            .ok_or_else(|| ServerError::UnknownEndpoint(endpoint.to_string()))?;
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        // Empty frame delimits the envelope, which is kept by REP socket
+        let envelope = id.to_be_bytes();
+        socket.send_multipart([&envelope[..], &[], &request.serialize()], 0)?;
+        self.sent.insert(id, self.clock.now());
+        Ok(id)
+    }
+
+    /// Waits for the reply to the request with the given `id`. Replies to
+    /// other requests which arrive meanwhile are kept until they are asked
+    /// for.
+    ///
+    /// If the client has a timeout set, fails with
+    /// [`transport::Error::TimedOut`] once the timeout passes since the
+    /// request was sent; the request is abandoned then. Requests abandoned
+    /// before, as well as unknown request ids, fail immediately.
+    pub fn recv(
+        &mut self,
+        endpoint: E,
+        id: RequestId,
+    ) -> Result<A::Reply, ServerError<A::FailureCodeExt>> {
+        self.expire();
+        let deadline = match self.sent.get(&id) {
+            None => return Err(transport::Error::TimedOut.into()),
+            Some(sent) => self.timeout.map(|timeout| *sent + timeout),
+        };
+        let raw = match self.pending.remove(&id) {
+            Some(raw) => raw,
+            None => loop {
+                let socket = self
+                    .sockets
+                    .get(&endpoint)
+                    .ok_or_else(|| ServerError::UnknownEndpoint(endpoint.to_string()))?;
+                if let Some(deadline) = deadline {
+                    let left = deadline.saturating_duration_since(self.clock.now());
+                    if socket.poll(zmq::POLLIN, left.as_millis() as i64)? == 0 {
+                        debug!("RPC request {} to endpoint {} has timed out", id, endpoint);
+                        self.sent.remove(&id);
+                        return Err(transport::Error::TimedOut.into());
+                    }
+                }
+                let mut parts = socket.recv_multipart(0)?;
+                if parts.len() != 3 || parts[0].len() != 8 || !parts[1].is_empty() {
+                    warn!("Ignoring malformed RPC reply from endpoint {}", endpoint);
+                    continue;
+                }
+                let raw = parts.pop().expect("number of parts is checked");
+                let mut envelope = [0u8; 8];
+                envelope.copy_from_slice(&parts[0]);
+                match RequestId::from_be_bytes(envelope) {
+                    reply_id if reply_id == id => break raw,
+                    reply_id if self.sent.contains_key(&reply_id) => {
+                        self.pending.insert(reply_id, raw);
+                    }
+                    reply_id => {
+                        debug!("Discarding reply to abandoned RPC request {}", reply_id);
+                    }
+                }
+            },
+        };
+        self.sent.remove(&id);
         let reply = (*self.unmarshaller.unmarshall(Cursor::new(raw))?).clone();
         Ok(reply)
     }
+
+    /// Sends request and waits for the reply to it
+    pub fn request(
+        &mut self,
+        endpoint: E,
+        request: A::Request,
+    ) -> Result<A::Reply, ServerError<A::FailureCodeExt>> {
+        let id = self.send(endpoint, request)?;
+        self.recv(endpoint, id)
+    }
+
+    /// Closes all client sockets, discarding the requests which were not yet
+    /// sent; see [`RpcClient::close`] for the details.
+    pub fn close(mut self) {
+        for (endpoint, socket) in self.sockets.drain() {
+            debug!("Closing RPC socket for endpoint {}", endpoint);
+            if let Err(err) = socket.set_linger(0) {
+                warn!("Unable to reset linger for RPC endpoint {}: {}", endpoint, err);
+            }
+        }
+    }
+
+    /// Abandons requests which were not replied within the timeout, dropping
+    /// their replies received meanwhile
+    fn expire(&mut self) {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        let now = self.clock.now();
+        let pending = &mut self.pending;
+        self.sent.retain(|id, sent| {
+            let alive = now.saturating_duration_since(*sent) < timeout;
+            if !alive {
+                pending.remove(id);
+            }
+            alive
+        });
+    }
+}
+
+impl<E, A> Drop for DealerClient<E, A>
+where
+    A: Api,
+    E: EndpointId,
+{
+    fn drop(&mut self) {
+        for endpoint in self.sockets.keys() {
+            debug!("Dropping RPC socket for endpoint {}", endpoint);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::any::Any;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::thread;
+
+    use internet2::RoutedFrame;
+
+    use super::*;
+    use crate::rpc::test::{TestApi, TestEndpoint, TestFailureCode, TestReply, TestRequest};
+    use crate::util::MockClock;
+
+    /// In-memory session answering the test requests
+    #[derive(Default)]
+    struct MemorySession {
+        replies: VecDeque<Vec<u8>>,
+    }
+
+    impl SendRecvMessage for MemorySession {
+        fn recv_raw_message(&mut self) -> Result<Vec<u8>, transport::Error> {
+            self.replies.pop_front().ok_or(transport::Error::TimedOut)
+        }
+
+        fn send_raw_message(&mut self, raw: &[u8]) -> Result<usize, transport::Error> {
+            let request = TestRequest::create_unmarshaller()
+                .unmarshall(Cursor::new(raw))
+                .map_err(|_| transport::Error::FrameBroken("unknown test request"))?;
+            self.replies.push_back(request.reply().serialize());
+            Ok(raw.len())
+        }
+
+        fn recv_routed_message(&mut self) -> Result<RoutedFrame, transport::Error> {
+            Err(transport::Error::RequiresLocalSocket)
+        }
+
+        fn send_routed_message(
+            &mut self,
+            _source: &[u8],
+            _route: &[u8],
+            _dest: &[u8],
+            _raw: &[u8],
+        ) -> Result<usize, transport::Error> {
+            Err(transport::Error::RequiresLocalSocket)
+        }
+
+        fn into_any(self: Box<Self>) -> Box<dyn Any> { self }
+    }
+
+    struct MemoryFactory;
+
+    impl SessionFactory for MemoryFactory {
+        fn create(
+            &self,
+            _addr: &ServiceAddr,
+        ) -> Result<Box<dyn SendRecvMessage + Send>, transport::Error> {
+            Ok(Box::new(MemorySession::default()))
+        }
+    }
+
+    fn addr(name: &str) -> ServiceAddr { ServiceAddr::Inproc(name.to_owned()) }
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn factory_is_send() {
+        assert_send::<Box<dyn SessionFactory>>();
+        assert_send::<Box<dyn SendRecvMessage + Send>>();
+    }
+
+    #[test]
+    fn memory_factory() {
+        let endpoints = map! { TestEndpoint::Node => addr("node") };
+        let mut client =
+            RpcClient::<TestEndpoint, TestApi>::with_factory(endpoints, MemoryFactory).unwrap();
+        assert_eq!(
+            client.request(TestEndpoint::Node, TestRequest::Ping(7)).unwrap(),
+            TestReply::Pong(7)
+        );
+        assert_eq!(
+            client.request(TestEndpoint::Node, TestRequest::Echo(s!("hi"))).unwrap(),
+            TestReply::Echo(s!("hi"))
+        );
+    }
+
+    /// Session counting the requests sent through it, which are answered
+    /// by the wrapped [`MemorySession`]
+    struct CountingSession {
+        sent: Arc<AtomicUsize>,
+        inner: MemorySession,
+    }
+
+    impl SendRecvMessage for CountingSession {
+        fn recv_raw_message(&mut self) -> Result<Vec<u8>, transport::Error> {
+            self.inner.recv_raw_message()
+        }
+
+        fn send_raw_message(&mut self, raw: &[u8]) -> Result<usize, transport::Error> {
+            self.sent.fetch_add(1, Ordering::SeqCst);
+            self.inner.send_raw_message(raw)
+        }
+
+        fn recv_routed_message(&mut self) -> Result<RoutedFrame, transport::Error> {
+            self.inner.recv_routed_message()
+        }
+
+        fn send_routed_message(
+            &mut self,
+            source: &[u8],
+            route: &[u8],
+            dest: &[u8],
+            raw: &[u8],
+        ) -> Result<usize, transport::Error> {
+            self.inner.send_routed_message(source, route, dest, raw)
+        }
+
+        fn into_any(self: Box<Self>) -> Box<dyn Any> { self }
+    }
+
+    struct CountingFactory(Arc<AtomicUsize>);
+
+    impl SessionFactory for CountingFactory {
+        fn create(
+            &self,
+            _addr: &ServiceAddr,
+        ) -> Result<Box<dyn SendRecvMessage + Send>, transport::Error> {
+            let session = CountingSession { sent: self.0.clone(), inner: none!() };
+            Ok(Box::new(session))
+        }
+    }
+
+    #[test]
+    fn wrong_endpoint_rejected() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let endpoints = map! {
+            TestEndpoint::Node => addr("node"),
+            TestEndpoint::Wallet => addr("wallet"),
+            TestEndpoint::Storage => addr("storage")
+        };
+        let factory = CountingFactory(sent.clone());
+        let mut client =
+            RpcClient::<TestEndpoint, TestApi>::with_factory(endpoints, factory).unwrap();
+
+        let err = client.request(TestEndpoint::Storage, TestRequest::Ping(1)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Configuration);
+        assert!(matches!(
+            err,
+            ServerError::WrongEndpointForRequest(ref endpoint, "test", "storage")
+                if endpoint == "Storage"
+        ));
+        assert_eq!(sent.load(Ordering::SeqCst), 0);
+
+        // Endpoints and requests which do not declare their API are not checked
+        let echo = TestRequest::Echo(s!("hi"));
+        assert_eq!(client.request(TestEndpoint::Storage, echo.clone()).unwrap(), echo.reply());
+        let ping = TestRequest::Ping(2);
+        assert_eq!(client.request(TestEndpoint::Wallet, ping.clone()).unwrap(), ping.reply());
+        assert_eq!(client.request(TestEndpoint::Node, ping.clone()).unwrap(), ping.reply());
+        assert_eq!(sent.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn dealer_wrong_endpoint_rejected() {
+        let endpoints = map! { TestEndpoint::Storage => addr("dealer-wrong-endpoint") };
+        let mut client = DealerClient::<TestEndpoint, TestApi>::with(endpoints).unwrap();
+        assert!(matches!(
+            client.send(TestEndpoint::Storage, TestRequest::Ping(1)),
+            Err(ServerError::WrongEndpointForRequest(..))
+        ));
+        assert_eq!(client.in_flight(), 0);
+    }
+
+    struct Ping(u64);
+
+    impl From<Ping> for TestRequest {
+        fn from(ping: Ping) -> Self { TestRequest::Ping(ping.0) }
+    }
+
+    /// Request which is declared with a wrong reply type
+    struct Shout(String);
+
+    impl From<Shout> for TestRequest {
+        fn from(shout: Shout) -> Self { TestRequest::Echo(shout.0) }
+    }
+
+    crate::typed_request! {
+        TestApi {
+            Ping => TestReply::Pong(u64),
+            Shout => TestReply::Pong(u64),
+        }
+    }
+
+    #[test]
+    fn request_typed() {
+        let endpoints = map! { TestEndpoint::Node => addr("node") };
+        let mut client =
+            RpcClient::<TestEndpoint, TestApi>::with_factory(endpoints, MemoryFactory).unwrap();
+        let nonce: u64 = client.request_typed(TestEndpoint::Node, Ping(7)).unwrap();
+        assert_eq!(nonce, 7);
+        assert!(matches!(
+            client.request_typed(TestEndpoint::Node, Shout(s!("hi"))),
+            Err(ServerError::UnexpectedServerResponse)
+        ));
+    }
+
+    /// Starts `REP` server on `inproc://{name}`, answering `count` test
+    /// requests, each after the `delay`
+    fn rep_server(name: &str, count: usize, delay: Duration) -> thread::JoinHandle<()> {
+        let socket = ZMQ_CONTEXT.socket(zmq::REP).unwrap();
+        socket.bind(&addr(name).zmq_connect_string()).unwrap();
+        thread::spawn(move || {
+            let unmarshaller = TestRequest::create_unmarshaller();
+            for _ in 0..count {
+                let raw = socket.recv_bytes(0).unwrap();
+                let request = unmarshaller.unmarshall(Cursor::new(raw)).unwrap();
+                thread::sleep(delay);
+                socket.send(request.reply().serialize(), 0).unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn dealer_matches_replies() {
+        let server = rep_server("dealer", 100, Duration::ZERO);
+        let endpoints = map! { TestEndpoint::Node => addr("dealer") };
+        let mut client = DealerClient::<TestEndpoint, TestApi>::with(endpoints).unwrap();
+
+        let requests = (0..100u64)
+            .map(|nonce| match nonce % 2 {
+                0 => TestRequest::Ping(nonce),
+                _ => TestRequest::Echo(nonce.to_string()),
+            })
+            .collect::<Vec<_>>();
+        let ids = requests
+            .iter()
+            .map(|request| client.send(TestEndpoint::Node, request.clone()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(client.in_flight(), 100);
+        // Replies arrive in the order of the requests, so all but the last
+        // one are kept while waiting for it
+        for (id, request) in ids.into_iter().zip(requests).rev() {
+            assert_eq!(client.recv(TestEndpoint::Node, id).unwrap(), request.reply());
+        }
+        assert_eq!(client.in_flight(), 0);
+        assert!(client.pending.is_empty());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn dealer_timeout() {
+        let server = rep_server("dealer-timeout", 2, Duration::from_millis(200));
+        let endpoints = map! { TestEndpoint::Node => addr("dealer-timeout") };
+        let timeout = Duration::from_millis(50);
+        let mut client =
+            DealerClient::<TestEndpoint, TestApi>::with_timeout(endpoints, timeout).unwrap();
+
+        let late = client.send(TestEndpoint::Node, TestRequest::Ping(1)).unwrap();
+        let err = client.recv(TestEndpoint::Node, late).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(client.in_flight(), 0);
+        // Abandoned request can't be received anymore
+        assert_eq!(client.recv(TestEndpoint::Node, late).unwrap_err().kind(), ErrorKind::TimedOut);
+
+        // The late reply to the abandoned request is discarded while waiting
+        // for the reply to the next one
+        client.set_timeout(Some(Duration::from_secs(5)));
+        let id = client.send(TestEndpoint::Node, TestRequest::Ping(2)).unwrap();
+        assert_eq!(client.recv(TestEndpoint::Node, id).unwrap(), TestReply::Pong(2));
+        assert!(client.pending.is_empty());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn dealer_timeout_by_clock() {
+        // No server is listening, so the requests are never replied
+        let endpoints = map! { TestEndpoint::Node => addr("dealer-clock") };
+        let timeout = Duration::from_secs(60);
+        let mut client =
+            DealerClient::<TestEndpoint, TestApi>::with_timeout(endpoints, timeout).unwrap();
+        let clock = MockClock::new();
+        client.set_clock(Arc::new(clock.clone()));
+
+        let first = client.send(TestEndpoint::Node, TestRequest::Ping(1)).unwrap();
+        clock.advance(Duration::from_secs(30));
+        let second = client.send(TestEndpoint::Node, TestRequest::Ping(2)).unwrap();
+        clock.advance(Duration::from_secs(31));
+        assert_eq!(client.recv(TestEndpoint::Node, first).unwrap_err().kind(), ErrorKind::TimedOut);
+        assert_eq!(client.in_flight(), 1);
+
+        clock.advance(Duration::from_secs(30));
+        let err = client.recv(TestEndpoint::Node, second).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(client.in_flight(), 0);
+    }
+
+    /// Session failing to send any message
+    struct BrokenSession;
+
+    impl SendRecvMessage for BrokenSession {
+        fn recv_raw_message(&mut self) -> Result<Vec<u8>, transport::Error> {
+            Err(transport::Error::SocketIo(io::ErrorKind::BrokenPipe))
+        }
+
+        fn send_raw_message(&mut self, _raw: &[u8]) -> Result<usize, transport::Error> {
+            Err(transport::Error::SocketIo(io::ErrorKind::BrokenPipe))
+        }
+
+        fn recv_routed_message(&mut self) -> Result<RoutedFrame, transport::Error> {
+            Err(transport::Error::RequiresLocalSocket)
+        }
+
+        fn send_routed_message(
+            &mut self,
+            _source: &[u8],
+            _route: &[u8],
+            _dest: &[u8],
+            _raw: &[u8],
+        ) -> Result<usize, transport::Error> {
+            Err(transport::Error::RequiresLocalSocket)
+        }
+
+        fn into_any(self: Box<Self>) -> Box<dyn Any> { self }
+    }
+
+    /// Factory creating up to `limit` broken sessions and failing afterwards
+    struct FailingFactory {
+        limit: AtomicUsize,
+    }
+
+    impl FailingFactory {
+        fn new(limit: usize) -> Self { Self { limit: AtomicUsize::new(limit) } }
+    }
+
+    impl SessionFactory for FailingFactory {
+        fn create(
+            &self,
+            _addr: &ServiceAddr,
+        ) -> Result<Box<dyn SendRecvMessage + Send>, transport::Error> {
+            let left = self.limit.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                n.checked_sub(1)
+            });
+            match left {
+                Ok(_) => Ok(Box::new(BrokenSession)),
+                Err(_) => Err(transport::Error::SocketIo(io::ErrorKind::ConnectionRefused)),
+            }
+        }
+    }
+
+    /// Session executing the requests, which drops the connection after the
+    /// first request if `drops` is set
+    struct DroppingSession {
+        executed: Arc<Mutex<Vec<TestRequest>>>,
+        replies: VecDeque<Vec<u8>>,
+        drops: bool,
+        dropped: bool,
+    }
+
+    impl SendRecvMessage for DroppingSession {
+        fn recv_raw_message(&mut self) -> Result<Vec<u8>, transport::Error> {
+            if self.dropped {
+                return Err(transport::Error::SocketIo(io::ErrorKind::ConnectionReset));
+            }
+            self.replies.pop_front().ok_or(transport::Error::TimedOut)
+        }
+
+        fn send_raw_message(&mut self, raw: &[u8]) -> Result<usize, transport::Error> {
+            if self.dropped {
+                return Err(transport::Error::SocketIo(io::ErrorKind::BrokenPipe));
+            }
+            let request = TestRequest::create_unmarshaller()
+                .unmarshall(Cursor::new(raw))
+                .map_err(|_| transport::Error::FrameBroken("unknown test request"))?;
+            let request = (*request).clone();
+            self.replies.push_back(request.reply().serialize());
+            self.executed.lock().unwrap().push(request);
+            self.dropped = self.drops;
+            Ok(raw.len())
+        }
+
+        fn recv_routed_message(&mut self) -> Result<RoutedFrame, transport::Error> {
+            Err(transport::Error::RequiresLocalSocket)
+        }
+
+        fn send_routed_message(
+            &mut self,
+            _source: &[u8],
+            _route: &[u8],
+            _dest: &[u8],
+            _raw: &[u8],
+        ) -> Result<usize, transport::Error> {
+            Err(transport::Error::RequiresLocalSocket)
+        }
+
+        fn into_any(self: Box<Self>) -> Box<dyn Any> { self }
+    }
+
+    /// Factory whose first session drops the connection after the first request
+    #[derive(Default)]
+    struct DroppingFactory {
+        executed: Arc<Mutex<Vec<TestRequest>>>,
+        created: AtomicUsize,
+    }
+
+    impl SessionFactory for DroppingFactory {
+        fn create(
+            &self,
+            _addr: &ServiceAddr,
+        ) -> Result<Box<dyn SendRecvMessage + Send>, transport::Error> {
+            Ok(Box::new(DroppingSession {
+                executed: self.executed.clone(),
+                replies: none!(),
+                drops: self.created.fetch_add(1, Ordering::SeqCst) == 0,
+                dropped: false,
+            }))
+        }
+    }
+
+    #[test]
+    fn reconnect_after_drop() {
+        let factory = DroppingFactory::default();
+        let executed = factory.executed.clone();
+        let endpoints = map! { TestEndpoint::Node => addr("node") };
+        let mut client =
+            RpcClient::<TestEndpoint, TestApi>::with_factory(endpoints, factory).unwrap();
+
+        // The request was sent before the connection was dropped, so it must
+        // not be repeated
+        let err = client.request(TestEndpoint::Node, TestRequest::Ping(1)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unreachable);
+        assert_eq!(*executed.lock().unwrap(), vec![TestRequest::Ping(1)]);
+
+        // The next request fails to be sent and is repeated over a new session
+        let reply = client.request(TestEndpoint::Node, TestRequest::Ping(2)).unwrap();
+        assert_eq!(reply, TestReply::Pong(2));
+        assert_eq!(*executed.lock().unwrap(), vec![TestRequest::Ping(1), TestRequest::Ping(2)]);
+    }
+
+    #[test]
+    fn client_is_send() { assert_send::<RpcClient<TestEndpoint, TestApi>>(); }
+
+    #[test]
+    fn resolved_endpoint_connection_failure() {
+        let mut client =
+            RpcClient::<TestEndpoint, TestApi>::with_factory(none!(), FailingFactory::new(0))
+                .unwrap();
+        client.set_resolver(|_| Some(addr("node")));
+        let err = client.request(TestEndpoint::Node, TestRequest::Ping(1)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+        // Endpoint is not registered, so the next request resolves it again
+        assert!(client.endpoints.is_empty());
+        let err = client.request(TestEndpoint::Node, TestRequest::Ping(2)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    /// API answering timed out requests with an empty echo
+    struct BestEffortApi;
+
+    impl Api for BestEffortApi {
+        type Request = TestRequest;
+        type Reply = TestReply;
+        type FailureCodeExt = TestFailureCode;
+
+        fn default_reply_on_timeout() -> Option<TestReply> { Some(TestReply::Echo(s!(""))) }
+    }
+
+    #[test]
+    fn default_reply_on_timeout() {
+        // Server receives nothing and never replies
+        let socket = ZMQ_CONTEXT.socket(zmq::REP).unwrap();
+        socket.bind(&addr("silent").zmq_connect_string()).unwrap();
+        let endpoints = map! { TestEndpoint::Node => addr("silent") };
+        let timeout = Duration::from_millis(100);
+
+        let mut client =
+            RpcClient::<TestEndpoint, BestEffortApi>::with_timeout(endpoints.clone(), timeout)
+                .unwrap();
+        let reply = client.request(TestEndpoint::Node, TestRequest::Ping(1)).unwrap();
+        assert_eq!(reply, TestReply::Echo(s!("")));
+
+        let mut client =
+            RpcClient::<TestEndpoint, TestApi>::with_timeout(endpoints, timeout).unwrap();
+        let err = client.request(TestEndpoint::Node, TestRequest::Ping(2)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn close_with_unreachable_peer() {
+        // Port which nobody listens on
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let endpoints = map! {
+            TestEndpoint::Node => ServiceAddr::Tcp(format!("127.0.0.1:{}", port).parse().unwrap())
+        };
+        let mut client = DealerClient::<TestEndpoint, TestApi>::with(endpoints).unwrap();
+        client.send(TestEndpoint::Node, TestRequest::Ping(1)).unwrap();
+        // Implicit drop would keep the default infinite linger
+        assert_eq!(client.sockets[&TestEndpoint::Node].get_linger().unwrap(), -1);
+
+        let start = Instant::now();
+        client.close();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn reconnect_failure() {
+        let endpoints = map! { TestEndpoint::Node => addr("node") };
+        let mut client =
+            RpcClient::<TestEndpoint, TestApi>::with_factory(endpoints, FailingFactory::new(1))
+                .unwrap();
+        let err = client.request(TestEndpoint::Node, TestRequest::Ping(1)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+        // Client for an endpoint lost in between must not panic either
+        client.endpoints.clear();
+        let err = client.request(TestEndpoint::Node, TestRequest::Ping(2)).unwrap_err();
+        assert!(matches!(err, ServerError::UnknownEndpoint(_)));
+    }
+
+    #[test]
+    fn late_endpoints() {
+        let mut client =
+            RpcClient::<TestEndpoint, TestApi>::with_factory(none!(), MemoryFactory).unwrap();
+        assert!(matches!(
+            client.request(TestEndpoint::Node, TestRequest::Ping(1)),
+            Err(ServerError::UnknownEndpoint(_))
+        ));
+        client.add_endpoint(TestEndpoint::Node, addr("node")).unwrap();
+        assert_eq!(
+            client.request(TestEndpoint::Node, TestRequest::Ping(1)).unwrap(),
+            TestReply::Pong(1)
+        );
+
+        client.set_resolver(|endpoint| match endpoint {
+            TestEndpoint::Wallet => Some(addr("wallet")),
+            _ => None,
+        });
+        assert_eq!(
+            client.request(TestEndpoint::Wallet, TestRequest::Ping(2)).unwrap(),
+            TestReply::Pong(2)
+        );
+        assert_eq!(client.endpoints[&TestEndpoint::Wallet], addr("wallet"));
+    }
 }
+
@@ -0,0 +1,355 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Transparent compression of the session messages.
+//!
+//! Messages sent by [`CompressedSession`] to the peers supporting compression
+//! are wrapped into an envelope: a message of the reserved
+//! [`COMPRESSED_MESSAGE_TYPE`] followed by a flag byte telling whether the
+//! rest of the message is compressed. Messages which are small or do not
+//! compress well are sent in the envelope as is.
+//!
+//! Application messages never use the reserved message type, so messages
+//! without the envelope are received as plain messages. This allows sessions
+//! created with [`CompressedSession::responding`] to interoperate both with
+//! the peers using compression and with the ones which don't: such sessions
+//! use the envelope only for the peers which have sent an envelope to them.
+
+use std::any::Any;
+use std::collections::HashSet;
+
+use internet2::{transport, RoutedFrame, SendRecvMessage};
+
+/// Default minimal size of a message which gets compressed
+pub const COMPRESSION_THRESHOLD: usize = 256;
+
+/// Message type of the compression envelope, taken from the range of the
+/// message types reserved for the system use
+pub const COMPRESSED_MESSAGE_TYPE: u16 = 0xFFF0;
+
+const FLAG_PLAIN: u8 = 0x00;
+const FLAG_COMPRESSED: u8 = 0x01;
+
+/// Size of the envelope header: message type and the flag byte
+const HEADER_LEN: usize = 3;
+
+/// Compression algorithm used by [`CompressedSession`]. Implementations
+/// usually wrap some compression library like zstd or lz4, keeping this
+/// library free of the compression dependencies.
+pub trait Compression {
+    /// Compresses `data`
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+
+    /// Decompresses `data` produced by [`Compression::compress`], returning
+    /// `None` if the data are corrupted.
+    fn decompress(&self, data: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Session wrapper compressing messages with the provided [`Compression`]
+/// algorithm
+pub struct CompressedSession<S, C>
+where
+    S: SendRecvMessage,
+    C: Compression,
+{
+    session: S,
+    compression: C,
+    threshold: Option<usize>,
+    /// Whether messages are wrapped into the envelope for all of the peers
+    initiating: bool,
+    /// Whether the peer of a non-routed session has sent us an envelope
+    peer_supported: bool,
+    /// Routed peers which have sent us an envelope
+    routed_peers: HashSet<Vec<u8>>,
+}
+
+impl<S, C> CompressedSession<S, C>
+where
+    S: SendRecvMessage,
+    C: Compression,
+{
+    /// Wraps `session`, compressing messages of [`COMPRESSION_THRESHOLD`] size
+    /// and above. All of the messages are sent in the compression envelope,
+    /// so the remote peers must use [`CompressedSession`] as well.
+    pub fn new(session: S, compression: C) -> Self {
+        Self {
+            session,
+            compression,
+            threshold: Some(COMPRESSION_THRESHOLD),
+            initiating: true,
+            peer_supported: false,
+            routed_peers: none!(),
+        }
+    }
+
+    /// Wraps `session` in the same way as [`CompressedSession::new`], but
+    /// uses the compression envelope only for the peers which have sent an
+    /// envelope first, sending plain messages to the rest of them. Suits
+    /// servers which have to support clients without compression.
+    pub fn responding(session: S, compression: C) -> Self {
+        Self { initiating: false, ..Self::new(session, compression) }
+    }
+
+    /// Sets minimal size of messages which get compressed; `None` disables
+    /// compression of the sent messages, while received compressed messages
+    /// are still decompressed.
+    pub fn set_threshold(&mut self, threshold: Option<usize>) { self.threshold = threshold; }
+
+    /// Returns the wrapped session
+    pub fn into_inner(self) -> S { self.session }
+
+    fn encode(&self, raw: &[u8], enveloped: bool) -> Vec<u8> {
+        if !enveloped {
+            return raw.to_vec();
+        }
+        let mut header = [0u8; HEADER_LEN];
+        header[..2].copy_from_slice(&COMPRESSED_MESSAGE_TYPE.to_le_bytes());
+        if let Some(threshold) = self.threshold {
+            if raw.len() >= threshold {
+                let compressed = self.compression.compress(raw);
+                if compressed.len() < raw.len() {
+                    trace!("Compressed message of {} bytes to {}", raw.len(), compressed.len());
+                    header[2] = FLAG_COMPRESSED;
+                    return [&header[..], &compressed].concat();
+                }
+            }
+        }
+        header[2] = FLAG_PLAIN;
+        [&header[..], raw].concat()
+    }
+
+    /// Unwraps received message from the envelope, if any, returning the
+    /// message and whether it was enveloped
+    fn decode(&self, data: Vec<u8>) -> Result<(Vec<u8>, bool), transport::Error> {
+        if !data.starts_with(&COMPRESSED_MESSAGE_TYPE.to_le_bytes()) {
+            return Ok((data, false));
+        }
+        match data.get(2) {
+            Some(&FLAG_PLAIN) => Ok((data[HEADER_LEN..].to_vec(), true)),
+            Some(&FLAG_COMPRESSED) => self
+                .compression
+                .decompress(&data[HEADER_LEN..])
+                .map(|data| (data, true))
+                .ok_or(transport::Error::FrameBroken("corrupted compressed message")),
+            Some(_) => Err(transport::Error::FrameBroken("unknown compression flag")),
+            None => Err(transport::Error::FrameBroken("missed compression flag")),
+        }
+    }
+}
+
+impl<S, C> SendRecvMessage for CompressedSession<S, C>
+where
+    S: SendRecvMessage + 'static,
+    C: Compression + 'static,
+{
+    fn recv_raw_message(&mut self) -> Result<Vec<u8>, transport::Error> {
+        let data = self.session.recv_raw_message()?;
+        let (data, enveloped) = self.decode(data)?;
+        self.peer_supported |= enveloped;
+        Ok(data)
+    }
+
+    fn send_raw_message(&mut self, raw: &[u8]) -> Result<usize, transport::Error> {
+        let data = self.encode(raw, self.initiating || self.peer_supported);
+        self.session.send_raw_message(&data)
+    }
+
+    fn recv_routed_message(&mut self) -> Result<RoutedFrame, transport::Error> {
+        let mut frame = self.session.recv_routed_message()?;
+        let (msg, enveloped) = self.decode(frame.msg)?;
+        if enveloped && !self.routed_peers.contains(&frame.src) {
+            self.routed_peers.insert(frame.src.clone());
+        }
+        frame.msg = msg;
+        Ok(frame)
+    }
+
+    fn send_routed_message(
+        &mut self,
+        source: &[u8],
+        route: &[u8],
+        dest: &[u8],
+        raw: &[u8],
+    ) -> Result<usize, transport::Error> {
+        let data = self.encode(raw, self.initiating || self.routed_peers.contains(dest));
+        self.session.send_routed_message(source, route, dest, &data)
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> { self }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    use super::*;
+
+    type Queue = Rc<RefCell<VecDeque<RoutedFrame>>>;
+
+    /// In-memory session delivering messages to its outbox queue
+    struct Pipe {
+        inbox: Queue,
+        outbox: Queue,
+    }
+
+    impl SendRecvMessage for Pipe {
+        fn recv_raw_message(&mut self) -> Result<Vec<u8>, transport::Error> {
+            self.recv_routed_message().map(|frame| frame.msg)
+        }
+
+        fn send_raw_message(&mut self, raw: &[u8]) -> Result<usize, transport::Error> {
+            self.send_routed_message(&[], &[], &[], raw)
+        }
+
+        fn recv_routed_message(&mut self) -> Result<RoutedFrame, transport::Error> {
+            self.inbox.borrow_mut().pop_front().ok_or(transport::Error::TimedOut)
+        }
+
+        fn send_routed_message(
+            &mut self,
+            source: &[u8],
+            route: &[u8],
+            dest: &[u8],
+            raw: &[u8],
+        ) -> Result<usize, transport::Error> {
+            self.outbox.borrow_mut().push_back(RoutedFrame {
+                hop: route.to_vec(),
+                src: source.to_vec(),
+                dst: dest.to_vec(),
+                msg: raw.to_vec(),
+            });
+            Ok(raw.len())
+        }
+
+        fn into_any(self: Box<Self>) -> Box<dyn Any> { self }
+    }
+
+    fn pipe() -> (Pipe, Pipe) {
+        let (a, b) = (Queue::default(), Queue::default());
+        (Pipe { inbox: a.clone(), outbox: b.clone() }, Pipe { inbox: b, outbox: a })
+    }
+
+    fn last_sent(queue: &Queue) -> Vec<u8> { queue.borrow().back().unwrap().msg.clone() }
+
+    /// Run-length encoding, compressing well the repetitive test messages
+    struct Rle;
+
+    impl Compression for Rle {
+        fn compress(&self, data: &[u8]) -> Vec<u8> {
+            let mut compressed = Vec::new();
+            for byte in data {
+                match compressed.len() {
+                    len if len > 0
+                        && compressed[len - 1] == *byte
+                        && compressed[len - 2] < u8::MAX =>
+                    {
+                        compressed[len - 2] += 1
+                    }
+                    _ => compressed.extend([1, *byte]),
+                }
+            }
+            compressed
+        }
+
+        fn decompress(&self, data: &[u8]) -> Option<Vec<u8>> {
+            if data.len() % 2 != 0 {
+                return None;
+            }
+            Some(data.chunks(2).flat_map(|run| vec![run[1]; run[0] as usize]).collect())
+        }
+    }
+
+    fn message(len: usize) -> Vec<u8> { [&[0x10, 0x00][..], &vec![0xAA; len]].concat() }
+
+    #[test]
+    fn round_trip() {
+        let (client, server) = pipe();
+        let (client_out, server_out) = (client.outbox.clone(), server.outbox.clone());
+        let mut client = CompressedSession::new(client, Rle);
+        let mut server = CompressedSession::responding(server, Rle);
+
+        let request = message(COMPRESSION_THRESHOLD);
+        client.send_raw_message(&request).unwrap();
+        let wire = last_sent(&client_out);
+        assert_eq!(&wire[..3], &[0xF0, 0xFF, FLAG_COMPRESSED]);
+        assert!(wire.len() < request.len());
+        assert_eq!(server.recv_raw_message().unwrap(), request);
+
+        let reply = message(1000);
+        server.send_raw_message(&reply).unwrap();
+        assert_eq!(&last_sent(&server_out)[..3], &[0xF0, 0xFF, FLAG_COMPRESSED]);
+        assert_eq!(client.recv_raw_message().unwrap(), reply);
+
+        let small = message(8);
+        server.send_raw_message(&small).unwrap();
+        assert_eq!(last_sent(&server_out), [&[0xF0, 0xFF, FLAG_PLAIN][..], &small].concat());
+        assert_eq!(client.recv_raw_message().unwrap(), small);
+    }
+
+    #[test]
+    fn plain_peer_interop() {
+        let (mut client, server) = pipe();
+        let server_out = server.outbox.clone();
+        let mut server = CompressedSession::responding(server, Rle);
+
+        let request = message(1000);
+        client.send_raw_message(&request).unwrap();
+        assert_eq!(server.recv_raw_message().unwrap(), request);
+
+        let reply = message(1000);
+        server.send_raw_message(&reply).unwrap();
+        assert_eq!(last_sent(&server_out), reply);
+        assert_eq!(client.recv_raw_message().unwrap(), reply);
+    }
+
+    #[test]
+    fn routed_peers() {
+        let (mut plain, server) = pipe();
+        let server_out = server.outbox.clone();
+        let mut compressed = CompressedSession::new(
+            Pipe { inbox: Queue::default(), outbox: plain.outbox.clone() },
+            Rle,
+        );
+        let mut server = CompressedSession::responding(server, Rle);
+
+        let msg = message(1000);
+        compressed.send_routed_message(b"alice", b"", b"server", &msg).unwrap();
+        plain.send_routed_message(b"bob", b"", b"server", &msg).unwrap();
+        for src in [&b"alice"[..], b"bob"] {
+            let frame = server.recv_routed_message().unwrap();
+            assert_eq!(frame.src, src);
+            assert_eq!(frame.msg, msg);
+        }
+
+        server.send_routed_message(b"server", b"", b"alice", &msg).unwrap();
+        assert_eq!(&last_sent(&server_out)[..3], &[0xF0, 0xFF, FLAG_COMPRESSED]);
+        server.send_routed_message(b"server", b"", b"bob", &msg).unwrap();
+        assert_eq!(last_sent(&server_out), msg);
+    }
+
+    #[test]
+    fn broken_envelope() {
+        let (mut client, server) = pipe();
+        let mut server = CompressedSession::responding(server, Rle);
+        for (data, err) in [
+            (&[0xF0, 0xFF, FLAG_COMPRESSED, 0x01][..], "corrupted compressed message"),
+            (&[0xF0, 0xFF, 0x07, 0x01], "unknown compression flag"),
+            (&[0xF0, 0xFF], "missed compression flag"),
+        ] {
+            client.send_raw_message(data).unwrap();
+            assert_eq!(server.recv_raw_message().unwrap_err(), transport::Error::FrameBroken(err));
+        }
+    }
+}
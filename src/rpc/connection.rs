@@ -11,18 +11,29 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
-use std::fmt::{Debug, Display};
+use std::fmt::{self, Debug, Display, Formatter};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
+use amplify::hex::ToHex;
 use internet2::addr::ServiceAddr;
-use internet2::presentation::{CreateUnmarshaller, Error, TypedEnum};
+use internet2::presentation::{CreateUnmarshaller, Error, TypedEnum, Unmarshall};
 use internet2::session::LocalSession;
-use internet2::{SendRecvMessage, ZmqSocketType};
+use internet2::{transport, SendRecvMessage, ZmqSocketType};
 
-use crate::rpc::FailureCodeExt;
+use crate::rpc::{FailureCodeExt, ServerError};
 use crate::ZMQ_CONTEXT;
 
 /// Marker trait for LNP RPC requests
-pub trait Request: Debug + Display + TypedEnum + CreateUnmarshaller {}
+pub trait Request: Debug + Display + TypedEnum + CreateUnmarshaller {
+    /// Name of the API the request belongs to, which is checked by the RPC
+    /// clients against [`EndpointId::api_name`] of the request destination.
+    /// Defaults to `None`, which disables the check.
+    ///
+    /// [`EndpointId::api_name`]: crate::rpc::EndpointId::api_name
+    fn api_name(&self) -> Option<&'static str> { None }
+}
 
 /// Marker trait for LNP RPC replies
 pub trait Reply: Debug + Display + TypedEnum + CreateUnmarshaller {}
@@ -37,6 +48,198 @@ pub trait Api {
 
     /// Extended failure codes which are service-specific
     type FailureCodeExt: FailureCodeExt;
+
+    /// Reply which is returned to the client instead of an error when the
+    /// remote endpoint does not reply within the session timeout. Defaults to
+    /// `None`, meaning timeouts are always reported as errors; APIs with
+    /// best-effort queries may provide e.g. an empty result here.
+    ///
+    /// Sessions time out only if they are created with a timeout, for
+    /// instance by the client constructed with [`RpcClient::with_timeout`] or
+    /// with [`ZmqSessionFactory::with_timeout`]; clients constructed with
+    /// [`RpcClient::with`] wait for the replies indefinitely.
+    ///
+    /// [`RpcClient::with_timeout`]: crate::rpc::client::RpcClient::with_timeout
+    /// [`ZmqSessionFactory::with_timeout`]: crate::rpc::client::ZmqSessionFactory::with_timeout
+    /// [`RpcClient::with`]: crate::rpc::client::RpcClient::with
+    fn default_reply_on_timeout() -> Option<Self::Reply> { None }
+}
+
+/// RPC request which is answered with a reply of a specific type, allowing
+/// [`RpcClient::request_typed`] to return the precise reply type instead of
+/// the whole [`Api::Reply`] enum.
+///
+/// Use [`typed_request!`](crate::typed_request) macro to declare request-reply pairs.
+///
+/// [`RpcClient::request_typed`]: crate::rpc::client::RpcClient::request_typed
+pub trait TypedRequest<A>: Into<A::Request>
+where
+    A: Api,
+{
+    /// Type of the reply expected for the request
+    type Reply;
+
+    /// Extracts the expected reply from the reply enum; returns `None` if the
+    /// server has replied with some other reply variant.
+    fn reply_from(reply: A::Reply) -> Option<Self::Reply>;
+}
+
+/// Declares request-reply pairs for an RPC API by implementing
+/// [`TypedRequest`]. Each request type must be convertible into the API
+/// request enum, and the reply is given as a reply enum variant with its
+/// payload type, like in `typed_request! { MyApi { GetInfo =>
+/// Reply::NodeInfo(NodeInfo), ListPeers => Reply::PeerList(Vec<NodeAddr>) } }`.
+/// The reply enum must be in scope of the macro invocation.
+///
+/// # Example
+///
+/// ```
+/// # #[macro_use] extern crate amplify;
+/// # #[macro_use] extern crate microservices;
+/// # use microservices::rpc::client::RpcClient;
+/// # use microservices::rpc::{Api, EndpointId, FailureCodeExt, Reply, Request};
+/// #
+/// # #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, From)]
+/// # struct FailureCode(u16);
+/// # impl From<FailureCode> for u16 {
+/// #     fn from(code: FailureCode) -> Self { code.0 }
+/// # }
+/// # impl FailureCodeExt for FailureCode {}
+/// #
+/// # #[derive(Clone, Debug, Display, internet2::Api)]
+/// # #[api(encoding = "strict")]
+/// # #[display(Debug)]
+/// # enum Req {
+/// #     #[api(type = 1)]
+/// #     GetHeight,
+/// #     #[api(type = 3)]
+/// #     GetName,
+/// # }
+/// # impl Request for Req {}
+/// #
+/// # #[derive(Clone, Debug, Display, internet2::Api)]
+/// # #[api(encoding = "strict")]
+/// # #[display(Debug)]
+/// # enum Rep {
+/// #     #[api(type = 2)]
+/// #     Height(u64),
+/// #     #[api(type = 4)]
+/// #     Name(String),
+/// # }
+/// # impl Reply for Rep {}
+/// #
+/// # struct NodeApi;
+/// # impl Api for NodeApi {
+/// #     type Request = Req;
+/// #     type Reply = Rep;
+/// #     type FailureCodeExt = FailureCode;
+/// # }
+/// #
+/// # #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+/// # #[display(Debug)]
+/// # enum Endpoint {
+/// #     Node,
+/// # }
+/// # impl EndpointId for Endpoint {}
+/// #
+/// struct GetHeight;
+/// impl From<GetHeight> for Req {
+///     fn from(_: GetHeight) -> Self { Req::GetHeight }
+/// }
+///
+/// typed_request! { NodeApi { GetHeight => Rep::Height(u64) } }
+///
+/// # fn main() {}
+/// fn height(client: &mut RpcClient<Endpoint, NodeApi>) -> u64 {
+///     client.request_typed(Endpoint::Node, GetHeight).unwrap()
+/// }
+/// ```
+///
+/// Typed requests return the declared reply type, so using the reply as a
+/// value of some other type is a compile-time error:
+///
+/// ```compile_fail
+/// # #[macro_use] extern crate amplify;
+/// # #[macro_use] extern crate microservices;
+/// # use microservices::rpc::client::RpcClient;
+/// # use microservices::rpc::{Api, EndpointId, FailureCodeExt, Reply, Request};
+/// #
+/// # #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, From)]
+/// # struct FailureCode(u16);
+/// # impl From<FailureCode> for u16 {
+/// #     fn from(code: FailureCode) -> Self { code.0 }
+/// # }
+/// # impl FailureCodeExt for FailureCode {}
+/// #
+/// # #[derive(Clone, Debug, Display, internet2::Api)]
+/// # #[api(encoding = "strict")]
+/// # #[display(Debug)]
+/// # enum Req {
+/// #     #[api(type = 1)]
+/// #     GetHeight,
+/// #     #[api(type = 3)]
+/// #     GetName,
+/// # }
+/// # impl Request for Req {}
+/// #
+/// # #[derive(Clone, Debug, Display, internet2::Api)]
+/// # #[api(encoding = "strict")]
+/// # #[display(Debug)]
+/// # enum Rep {
+/// #     #[api(type = 2)]
+/// #     Height(u64),
+/// #     #[api(type = 4)]
+/// #     Name(String),
+/// # }
+/// # impl Reply for Rep {}
+/// #
+/// # struct NodeApi;
+/// # impl Api for NodeApi {
+/// #     type Request = Req;
+/// #     type Reply = Rep;
+/// #     type FailureCodeExt = FailureCode;
+/// # }
+/// #
+/// # #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+/// # #[display(Debug)]
+/// # enum Endpoint {
+/// #     Node,
+/// # }
+/// # impl EndpointId for Endpoint {}
+/// #
+/// struct GetHeight;
+/// impl From<GetHeight> for Req {
+///     fn from(_: GetHeight) -> Self { Req::GetHeight }
+/// }
+///
+/// typed_request! { NodeApi { GetHeight => Rep::Height(u64) } }
+///
+/// # fn main() {}
+/// fn height(client: &mut RpcClient<Endpoint, NodeApi>) -> u64 {
+///     // The reply to `GetHeight` is `u64`, so it can't be used as a string
+///     let name: String = client.request_typed(Endpoint::Node, GetHeight).unwrap();
+///     name.len() as u64
+/// }
+/// ```
+#[macro_export]
+macro_rules! typed_request {
+    ($api:ty { $( $req:ty => $enum:ident :: $variant:ident ($reply:ty) ),+ $(,)? }) => {
+        $(
+            impl $crate::rpc::TypedRequest<$api> for $req {
+                type Reply = $reply;
+
+                #[allow(unreachable_patterns)]
+                fn reply_from(
+                    reply: <$api as $crate::rpc::Api>::Reply,
+                ) -> Option<Self::Reply> {
+                    match reply {
+                        $enum::$variant(payload) => Some(payload),
+                        _ => None,
+                    }
+                }
+            }
+        )+
+    };
 }
 
 #[allow(dead_code)]
@@ -79,4 +282,395 @@ where
         )?);
         Ok(Self { api, session })
     }
+
+    /// Connects to the remote RPC endpoint, bounding both connection
+    /// establishment and each of the following message exchanges with
+    /// `timeout`. If the connection handshake with the remote endpoint does
+    /// not complete within `timeout`, fails with
+    /// [`ServerError::ConnectTimeout`]; message exchanges exceeding it fail
+    /// with [`transport::Error::TimedOut`] instead of blocking indefinitely on
+    /// an unreachable peer.
+    ///
+    /// In-process (`inproc://`) endpoints are connected immediately, so only
+    /// the message exchanges are bounded for them. `local` address is not
+    /// used by the RPC sockets and is accepted for symmetry with
+    /// [`RpcConnection::connect`].
+    pub fn connect_timeout(
+        api: A,
+        remote: &ServiceAddr,
+        _local: &ServiceAddr,
+        timeout: Duration,
+    ) -> Result<Self, ServerError<A::FailureCodeExt>> {
+        let session = open_timeout(ZmqSocketType::Req, remote, timeout)?;
+        Ok(Self { api, session: Box::new(session) })
+    }
+
+    /// Accepts connection from the remote RPC client, failing with
+    /// [`ServerError::ConnectTimeout`] if no client has connected within
+    /// `timeout`, and bounding each of the following message exchanges with
+    /// `timeout`; see [`RpcConnection::connect_timeout`] for the details.
+    pub fn accept_timeout(
+        api: A,
+        remote: &ServiceAddr,
+        _local: &ServiceAddr,
+        timeout: Duration,
+    ) -> Result<Self, ServerError<A::FailureCodeExt>> {
+        let session = open_timeout(ZmqSocketType::Rep, remote, timeout)?;
+        Ok(Self { api, session: Box::new(session) })
+    }
+
+    /// Sends request to the remote RPC endpoint
+    pub fn send_request(&mut self, request: &A::Request) -> Result<usize, Error> {
+        Ok(self.session.send_raw_message(&request.serialize())?)
+    }
+
+    /// Receives reply to the request sent with [`RpcConnection::send_request`]
+    pub fn recv_reply(&mut self) -> Result<A::Reply, Error> {
+        let raw = self.session.recv_raw_message()?;
+        Ok((*A::Reply::create_unmarshaller().unmarshall(Cursor::new(raw))?).clone())
+    }
+
+    /// Receives request from the remote RPC client
+    pub fn recv_request(&mut self) -> Result<A::Request, Error> {
+        let raw = self.session.recv_raw_message()?;
+        Ok((*A::Request::create_unmarshaller().unmarshall(Cursor::new(raw))?).clone())
+    }
+
+    /// Sends reply to the request received with [`RpcConnection::recv_request`]
+    pub fn send_reply(&mut self, reply: &A::Reply) -> Result<usize, Error> {
+        Ok(self.session.send_raw_message(&reply.serialize())?)
+    }
 }
+
+/// ZMQ socket tuning options applied to the sockets created for the RPC and
+/// ESB sessions. Options set to `None` keep ZMQ defaults (high-water marks of
+/// 1000 messages and infinite linger).
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct SocketOptions {
+    /// Time for which messages which were not yet sent are kept in memory
+    /// after the socket is closed
+    pub linger: Option<Duration>,
+
+    /// Maximum number of outbound messages queued for a single peer; zero
+    /// means no limit
+    pub sndhwm: Option<i32>,
+
+    /// Maximum number of inbound messages queued for a single peer; zero
+    /// means no limit
+    pub rcvhwm: Option<i32>,
+
+    /// ZMQ CURVE authentication and encryption of the socket connections
+    pub curve: Option<CurveConfig>,
+}
+
+/// Keys for ZMQ CURVE authentication and encryption, requiring libzmq to be
+/// built with CURVE support
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct CurveConfig {
+    /// Public key of the CURVE server the socket connects to; `None` makes
+    /// the socket act as a CURVE server itself
+    pub server_key: Option<[u8; 32]>,
+
+    /// Public key of the socket
+    pub public_key: [u8; 32],
+
+    /// Secret key of the socket
+    pub secret_key: [u8; 32],
+}
+
+impl Debug for CurveConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CurveConfig")
+            .field("server_key", &self.server_key.map(|key| key.to_hex()))
+            .field("public_key", &self.public_key.to_hex())
+            .field("secret_key", &"<secret>")
+            .finish()
+    }
+}
+
+impl CurveConfig {
+    /// Applies CURVE keys to the ZMQ socket
+    pub fn apply(&self, socket: &zmq::Socket) -> Result<(), transport::Error> {
+        match self.server_key {
+            None => socket.set_curve_server(true)?,
+            Some(server_key) => {
+                socket.set_curve_serverkey(&server_key)?;
+                socket.set_curve_publickey(&self.public_key)?;
+            }
+        }
+        socket.set_curve_secretkey(&self.secret_key)?;
+        Ok(())
+    }
+}
+
+impl SocketOptions {
+    /// Applies options to the ZMQ socket
+    pub fn apply(&self, socket: &zmq::Socket) -> Result<(), transport::Error> {
+        if let Some(linger) = self.linger {
+            socket.set_linger(linger.as_millis().min(i32::MAX as u128) as i32)?;
+        }
+        if let Some(hwm) = self.sndhwm {
+            socket.set_sndhwm(hwm)?;
+        }
+        if let Some(hwm) = self.rcvhwm {
+            socket.set_rcvhwm(hwm)?;
+        }
+        if let Some(curve) = self.curve {
+            curve.apply(socket)?;
+        }
+        Ok(())
+    }
+
+    /// Creates session of `api_type` connected (or bound) to `remote`, with
+    /// the options and `identity` applied to its socket before the connection
+    /// is established.
+    ///
+    /// The session is constructed from the already connected socket, so it
+    /// does not support identity change with [`LocalSession::set_identity`];
+    /// a new session must be created with this method instead.
+    pub fn connect(
+        &self,
+        api_type: ZmqSocketType,
+        remote: &ServiceAddr,
+        identity: Option<&[u8]>,
+    ) -> Result<LocalSession, transport::Error> {
+        let socket = ZMQ_CONTEXT.socket(api_type.socket_type())?;
+        self.apply(&socket)?;
+        if let Some(identity) = identity {
+            socket.set_identity(identity)?;
+        }
+        let endpoint = remote.zmq_connect_string();
+        match api_type {
+            ZmqSocketType::Rep | ZmqSocketType::Pub | ZmqSocketType::RouterBind => {
+                socket.bind(&endpoint)?
+            }
+            ZmqSocketType::Req | ZmqSocketType::Sub | ZmqSocketType::RouterConnect => {
+                socket.connect(&endpoint)?
+            }
+            _ => return Err(transport::Error::RequiresLocalSocket),
+        }
+        Ok(LocalSession::with_zmq_socket(api_type, socket))
+    }
+}
+
+/// Creates session of `api_type` with connect, send and receive timeouts set,
+/// connects (or binds) it to `remote` and waits for the connection handshake
+/// with the remote endpoint to complete for no longer than `timeout`.
+fn open_timeout<Ext>(
+    api_type: ZmqSocketType,
+    remote: &ServiceAddr,
+    timeout: Duration,
+) -> Result<LocalSession, ServerError<Ext>>
+where
+    Ext: FailureCodeExt,
+{
+    static MONITOR_ID: AtomicUsize = AtomicUsize::new(0);
+
+    let millis = timeout.as_millis().min(i32::MAX as u128) as i32;
+    let socket = ZMQ_CONTEXT.socket(api_type.socket_type())?;
+    socket.set_connect_timeout(millis)?;
+    socket.set_sndtimeo(millis)?;
+    socket.set_rcvtimeo(millis)?;
+    // Sockets failed to connect must not block the process exit
+    socket.set_linger(0)?;
+
+    // ZMQ connects asynchronously, so the connection establishment is
+    // detected with the socket monitor
+    let monitor = match remote {
+        ServiceAddr::Inproc(_) => None,
+        _ => {
+            let id = MONITOR_ID.fetch_add(1, Ordering::Relaxed);
+            let endpoint = format!("inproc://rpc-connect-monitor-{}", id);
+            socket.monitor(&endpoint, zmq::SocketEvent::HANDSHAKE_SUCCEEDED as i32)?;
+            let monitor = ZMQ_CONTEXT.socket(zmq::PAIR)?;
+            monitor.connect(&endpoint)?;
+            Some((monitor, endpoint))
+        }
+    };
+
+    let endpoint = remote.zmq_connect_string();
+    match api_type {
+        ZmqSocketType::Rep => socket.bind(&endpoint)?,
+        ZmqSocketType::Req => socket.connect(&endpoint)?,
+        _ => return Err(transport::Error::RequiresLocalSocket.into()),
+    }
+
+    if let Some((monitor, endpoint)) = monitor {
+        let connected = monitor.poll(zmq::POLLIN, millis as i64)? > 0;
+        // Monitor can't be removed with the ZMQ bindings, so it is replaced
+        // with the one which does not report any events
+        socket.monitor(&format!("{}-stop", endpoint), 0)?;
+        if !connected {
+            return Err(ServerError::ConnectTimeout(remote.to_string()));
+        }
+    }
+    Ok(LocalSession::with_zmq_socket(api_type, socket))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::rpc::test::{TestApi, TestRequest};
+    use crate::rpc::ErrorKind;
+
+    fn free_tcp_addr() -> ServiceAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        ServiceAddr::Tcp(listener.local_addr().unwrap())
+    }
+
+    #[test]
+    fn connect_timeout_fires() {
+        // Listener accepting TCP connections, but never completing ZMQ
+        // handshake
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let remote = ServiceAddr::Tcp(listener.local_addr().unwrap());
+        let local = ServiceAddr::Inproc(s!("connect-timeout"));
+
+        let start = Instant::now();
+        let err = RpcConnection::connect_timeout(
+            TestApi,
+            &remote,
+            &local,
+            Duration::from_millis(200),
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(err, ServerError::ConnectTimeout(_)));
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn connect_timeout_exchange() {
+        let remote = free_tcp_addr();
+        let local = ServiceAddr::Inproc(s!("connect-timeout-exchange"));
+        let timeout = Duration::from_secs(5);
+
+        let server = {
+            let (remote, local) = (remote.clone(), local.clone());
+            thread::spawn(move || {
+                let mut server =
+                    RpcConnection::accept_timeout(TestApi, &remote, &local, timeout).unwrap();
+                let request = server.recv_request().unwrap();
+                server.send_reply(&request.reply()).unwrap();
+            })
+        };
+        let mut client = RpcConnection::connect_timeout(TestApi, &remote, &local, timeout).unwrap();
+        client.send_request(&TestRequest::Ping(5)).unwrap();
+        assert_eq!(client.recv_reply().unwrap(), TestRequest::Ping(5).reply());
+        server.join().unwrap();
+    }
+
+    /// Publishes 100 messages to a subscriber which does not read them, and
+    /// returns the number of the messages which were queued for it
+    fn published(name: &str, options: SocketOptions) -> usize {
+        let remote = ServiceAddr::Inproc(name.to_owned());
+        let publisher = options.connect(ZmqSocketType::Pub, &remote, None).unwrap();
+        let publisher = publisher.as_socket();
+        let subscriber = ZMQ_CONTEXT.socket(zmq::SUB).unwrap();
+        if let Some(hwm) = options.rcvhwm {
+            subscriber.set_rcvhwm(hwm).unwrap();
+        }
+        subscriber.set_subscribe(b"").unwrap();
+        subscriber.set_rcvtimeo(100).unwrap();
+        subscriber.connect(&remote.zmq_connect_string()).unwrap();
+
+        // Waiting for the subscription to reach the publisher
+        let mut subscribed = false;
+        for _ in 0..100 {
+            publisher.send(&b"probe"[..], 0).unwrap();
+            if subscriber.recv_bytes(zmq::DONTWAIT).is_ok() {
+                subscribed = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(subscribed);
+        while subscriber.recv_bytes(0).is_ok() {}
+
+        for _ in 0..100 {
+            // PUB sockets never block, dropping messages above high-water mark
+            publisher.send(&b"message"[..], zmq::DONTWAIT).unwrap();
+        }
+        let mut count = 0;
+        while subscriber.recv_bytes(0).is_ok() {
+            count += 1;
+        }
+        count
+    }
+
+    #[test]
+    fn socket_options_hwm() {
+        assert_eq!(published("hwm-default", none!()), 100);
+
+        let options = SocketOptions {
+            linger: Some(Duration::from_millis(0)),
+            sndhwm: Some(1),
+            rcvhwm: Some(1),
+            curve: None,
+        };
+        let session = options
+            .connect(ZmqSocketType::Rep, &ServiceAddr::Inproc(s!("hwm-options")), Some(b"id"))
+            .unwrap();
+        let socket = session.as_socket();
+        assert_eq!(socket.get_linger().unwrap(), 0);
+        assert_eq!(socket.get_sndhwm().unwrap(), 1);
+        assert_eq!(socket.get_rcvhwm().unwrap(), 1);
+        assert_eq!(socket.get_identity().unwrap(), b"id");
+
+        let count = published("hwm-tiny", options);
+        assert!(count > 0 && count < 10, "{} messages were queued", count);
+    }
+
+    /// Options of the socket using CURVE, with the socket acting as a server
+    /// if no `server_key` is given
+    fn curve_options(keys: &zmq::CurveKeyPair, server_key: Option<[u8; 32]>) -> SocketOptions {
+        SocketOptions {
+            linger: Some(Duration::from_millis(0)),
+            curve: Some(CurveConfig {
+                server_key,
+                public_key: keys.public_key,
+                secret_key: keys.secret_key,
+            }),
+            ..none!()
+        }
+    }
+
+    #[test]
+    fn curve_handshake() {
+        if zmq::has("curve") != Some(true) {
+            eprintln!("libzmq is built without CURVE support; skipping the test");
+            return;
+        }
+        let server_keys = zmq::CurveKeyPair::new().unwrap();
+        let client_keys = zmq::CurveKeyPair::new().unwrap();
+        let remote = free_tcp_addr();
+        let mut server =
+            curve_options(&server_keys, None).connect(ZmqSocketType::Rep, &remote, None).unwrap();
+        server.as_socket().set_rcvtimeo(5000).unwrap();
+
+        // Client which does not know the server key can't connect
+        let impostor_keys = zmq::CurveKeyPair::new().unwrap();
+        let mut impostor = curve_options(&client_keys, Some(impostor_keys.public_key))
+            .connect(ZmqSocketType::Req, &remote, None)
+            .unwrap();
+        impostor.as_socket().set_rcvtimeo(200).unwrap();
+        impostor.send_raw_message(b"ping").unwrap();
+        assert!(impostor.recv_raw_message().is_err());
+
+        let mut client = curve_options(&client_keys, Some(server_keys.public_key))
+            .connect(ZmqSocketType::Req, &remote, None)
+            .unwrap();
+        client.as_socket().set_rcvtimeo(5000).unwrap();
+        client.send_raw_message(b"ping").unwrap();
+        assert_eq!(server.recv_raw_message().unwrap(), b"ping");
+        server.send_raw_message(b"pong").unwrap();
+        assert_eq!(client.recv_raw_message().unwrap(), b"pong");
+    }
+}
+
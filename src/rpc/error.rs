@@ -13,7 +13,7 @@
 
 use std::fmt::{self, Debug, Display, Formatter};
 use std::hash::Hash;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::num::ParseIntError;
 use std::str::FromStr;
 
@@ -157,6 +157,98 @@ where
     pub info: String,
 }
 
+/// Classification of RPC and ESB errors, allowing to decide on retries and
+/// alerting without matching on the error details.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display)]
+#[display(doc_comments)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// connection refused by the remote endpoint
+    ConnectionRefused,
+
+    /// remote endpoint is offline or unreachable
+    Unreachable,
+
+    /// operation timed out
+    TimedOut,
+
+    /// protocol violation, message framing or encoding error
+    Protocol,
+
+    /// operation is not supported by the transport
+    Unsupported,
+
+    /// invalid local configuration (unknown endpoint, address in use etc)
+    Configuration,
+
+    /// failure reported by the remote service
+    Remote,
+
+    /// input/output error
+    Io,
+
+    /// other error
+    Other,
+}
+
+impl From<&transport::Error> for ErrorKind {
+    fn from(err: &transport::Error) -> Self {
+        match err {
+            transport::Error::SocketIo(kind) => match kind {
+                io::ErrorKind::ConnectionRefused => ErrorKind::ConnectionRefused,
+                io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::BrokenPipe => ErrorKind::Unreachable,
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ErrorKind::TimedOut,
+                io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => ErrorKind::Protocol,
+                io::ErrorKind::Unsupported => ErrorKind::Unsupported,
+                io::ErrorKind::AddrInUse
+                | io::ErrorKind::AddrNotAvailable
+                | io::ErrorKind::InvalidInput => ErrorKind::Configuration,
+                _ => ErrorKind::Io,
+            },
+            transport::Error::Zmq(err) => match zmq::Error::from(*err) {
+                zmq::Error::ECONNREFUSED => ErrorKind::ConnectionRefused,
+                zmq::Error::EHOSTUNREACH | zmq::Error::ENETDOWN | zmq::Error::ENOTCONN => {
+                    ErrorKind::Unreachable
+                }
+                zmq::Error::EAGAIN => ErrorKind::TimedOut,
+                zmq::Error::EPROTO
+                | zmq::Error::EFSM
+                | zmq::Error::ENOCOMPATPROTO
+                | zmq::Error::EMSGSIZE => ErrorKind::Protocol,
+                zmq::Error::ENOTSUP | zmq::Error::EPROTONOSUPPORT => ErrorKind::Unsupported,
+                zmq::Error::EADDRINUSE | zmq::Error::EADDRNOTAVAIL | zmq::Error::EINVAL => {
+                    ErrorKind::Configuration
+                }
+                _ => ErrorKind::Other,
+            },
+            transport::Error::ServiceOffline => ErrorKind::Unreachable,
+            transport::Error::TimedOut => ErrorKind::TimedOut,
+            transport::Error::OversizedFrame(_)
+            | transport::Error::FrameTooSmall(_)
+            | transport::Error::FrameBroken(_)
+            | transport::Error::InvalidLength { .. }
+            | transport::Error::NoNoiseHeader
+            | transport::Error::Handshake(_) => ErrorKind::Protocol,
+            transport::Error::RequiresLocalSocket
+            | transport::Error::TorNotSupportedYet
+            | transport::Error::KeygenFeatureRequired(_) => ErrorKind::Unsupported,
+        }
+    }
+}
+
+impl From<&presentation::Error> for ErrorKind {
+    fn from(err: &presentation::Error) -> Self {
+        match err {
+            presentation::Error::Transport(err) => err.into(),
+            presentation::Error::Io(_) => ErrorKind::Io,
+            _ => ErrorKind::Protocol,
+        }
+    }
+}
+
 /// Errors happening with RPC APIs received by the server, but originating from the client
 /// connection.
 #[derive(Clone, Debug, Display, Error, From)]
@@ -175,6 +267,17 @@ pub enum ClientError {
     Transport(transport::Error),
 }
 
+impl ClientError {
+    /// Classifies the error
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ClientError::UnexpectedRequest => ErrorKind::Protocol,
+            ClientError::Presentation(err) => err.into(),
+            ClientError::Transport(err) => err.into(),
+        }
+    }
+}
+
 impl From<zmq::Error> for ClientError {
     fn from(err: zmq::Error) -> Self { ClientError::Transport(transport::Error::from(err)) }
 }
@@ -191,6 +294,7 @@ impl From<presentation::Error> for ClientError {
 /// Errors happening with RPC APIs on the server side and returned to the client
 #[derive(Clone, Debug, Display, Error, From)]
 #[display(doc_comments)]
+#[non_exhaustive]
 pub enum ServerError<Ext>
 where
     Ext: FailureCodeExt,
@@ -214,6 +318,32 @@ where
 
     /// provided RPC endpoint {0} is unknown
     UnknownEndpoint(String),
+
+    /// request of `{1}` API can't be sent to RPC endpoint {0}, which serves
+    /// `{2}` API
+    WrongEndpointForRequest(String, &'static str, &'static str),
+
+    /// connection with RPC endpoint {0} was not established in time
+    ConnectTimeout(String),
+}
+
+impl<Ext> ServerError<Ext>
+where
+    Ext: FailureCodeExt,
+{
+    /// Classifies the error
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ServerError::UnexpectedServerResponse => ErrorKind::Protocol,
+            ServerError::ServerFailure(_) => ErrorKind::Remote,
+            ServerError::Presentation(err) => err.into(),
+            ServerError::Transport(err) => err.into(),
+            ServerError::UnknownEndpoint(_) | ServerError::WrongEndpointForRequest(..) => {
+                ErrorKind::Configuration
+            }
+            ServerError::ConnectTimeout(_) => ErrorKind::TimedOut,
+        }
+    }
 }
 
 impl<Ext> From<zmq::Error> for ServerError<Ext>
@@ -254,3 +384,26 @@ where
         Failure { code: FailureCode::Runtime, info: err.to_string() }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::test::TestFailureCode;
+
+    #[test]
+    fn zmq_error_kinds() {
+        for (err, kind) in [
+            (zmq::Error::EAGAIN, ErrorKind::TimedOut),
+            (zmq::Error::ECONNREFUSED, ErrorKind::ConnectionRefused),
+            (zmq::Error::EHOSTUNREACH, ErrorKind::Unreachable),
+            (zmq::Error::ETERM, ErrorKind::Other),
+            (zmq::Error::EPROTO, ErrorKind::Protocol),
+            (zmq::Error::EADDRINUSE, ErrorKind::Configuration),
+            (zmq::Error::ENOTSUP, ErrorKind::Unsupported),
+        ] {
+            assert_eq!(ErrorKind::from(&transport::Error::from(err)), kind, "{}", err);
+            let err = ServerError::<TestFailureCode>::from(transport::Error::from(err));
+            assert_eq!(err.kind(), kind);
+        }
+    }
+}
@@ -13,13 +13,125 @@
 
 #[cfg(feature = "client")]
 pub mod client;
+mod compress;
 mod connection;
 mod error;
 #[cfg(feature = "node")]
 pub mod server;
+mod stream;
 
-pub use connection::{Api, Reply, Request, RpcConnection};
-pub use error::{ClientError, Failure, FailureCode, FailureCodeExt, ServerError};
+pub use compress::{
+    CompressedSession, Compression, COMPRESSED_MESSAGE_TYPE, COMPRESSION_THRESHOLD,
+};
+pub use connection::{Api, CurveConfig, Reply, Request, RpcConnection, SocketOptions, TypedRequest};
+pub use error::{ClientError, ErrorKind, Failure, FailureCode, FailureCodeExt, ServerError};
+pub use stream::{SessionReader, SessionWriter, STREAM_CHUNK_SIZE};
 
 /// Marker traits for endpoint identifiers lists
-pub trait EndpointId: Copy + Eq + std::hash::Hash + std::fmt::Display {}
+pub trait EndpointId: Copy + Eq + std::hash::Hash + std::fmt::Display {
+    /// Name of the API served by the endpoint. RPC clients refuse to send the
+    /// endpoint requests declaring a different [`Request::api_name`], failing
+    /// with [`ServerError::WrongEndpointForRequest`]. Defaults to `None`,
+    /// which disables the check.
+    fn api_name(&self) -> Option<&'static str> { None }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    //! RPC API shared by the RPC and ESB tests
+
+    // Message type constants and parsers generated by the API derive are
+    // not used by the tests
+    #![allow(dead_code)]
+
+    use super::{Api, Failure, FailureCodeExt, Reply, Request};
+
+    #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, From)]
+    pub struct TestFailureCode(u16);
+
+    impl From<TestFailureCode> for u16 {
+        fn from(code: TestFailureCode) -> Self { code.0 }
+    }
+
+    impl FailureCodeExt for TestFailureCode {}
+
+    #[derive(Clone, PartialEq, Eq, Debug, Display, internet2::Api)]
+    #[api(encoding = "strict")]
+    #[display(Debug)]
+    pub enum TestRequest {
+        #[api(type = 0x0001)]
+        Ping(u64),
+
+        #[api(type = 0x0003)]
+        Echo(String),
+    }
+
+    /// Pings belong to the `test` API, while echo requests may be sent to any
+    /// endpoint
+    impl Request for TestRequest {
+        fn api_name(&self) -> Option<&'static str> {
+            match self {
+                TestRequest::Ping(_) => Some("test"),
+                TestRequest::Echo(_) => None,
+            }
+        }
+    }
+
+    #[derive(Clone, PartialEq, Eq, Debug, Display, internet2::Api)]
+    #[api(encoding = "strict")]
+    #[display(Debug)]
+    pub enum TestReply {
+        #[api(type = 0x0002)]
+        Pong(u64),
+
+        #[api(type = 0x0004)]
+        Echo(String),
+
+        #[api(type = 0x0006)]
+        Failure(Failure<TestFailureCode>),
+    }
+
+    impl Reply for TestReply {}
+
+    impl From<Failure<TestFailureCode>> for TestReply {
+        fn from(failure: Failure<TestFailureCode>) -> Self { TestReply::Failure(failure) }
+    }
+
+    impl TestRequest {
+        /// Reply sent by the test servers to the request
+        pub fn reply(&self) -> TestReply {
+            match self {
+                TestRequest::Ping(nonce) => TestReply::Pong(*nonce),
+                TestRequest::Echo(text) => TestReply::Echo(text.clone()),
+            }
+        }
+    }
+
+    pub struct TestApi;
+
+    impl Api for TestApi {
+        type Request = TestRequest;
+        type Reply = TestReply;
+        type FailureCodeExt = TestFailureCode;
+    }
+
+    #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+    #[display(Debug)]
+    pub enum TestEndpoint {
+        Node,
+        Wallet,
+        Storage,
+    }
+
+    /// Node serves the `test` API and storage serves some other one, while
+    /// the wallet does not declare its API
+    impl super::EndpointId for TestEndpoint {
+        fn api_name(&self) -> Option<&'static str> {
+            match self {
+                TestEndpoint::Node => Some("test"),
+                TestEndpoint::Wallet => None,
+                TestEndpoint::Storage => Some("storage"),
+            }
+        }
+    }
+}
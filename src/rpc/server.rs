@@ -51,6 +51,31 @@ where
     fn handle_err(&mut self, error: ClientError) -> Result<(), ClientError>;
 }
 
+/// Check performed by [`RpcServer`] before passing the request to the
+/// [`Handler`], allowing cross-cutting validation (like request size limits or
+/// authorization) to be implemented once for all requests. Implemented for
+/// closures taking the endpoint and a reference to the request.
+pub trait RequestFilter<E, A>
+where
+    E: EndpointId,
+    A: Api,
+{
+    /// Checks `request` received via `endpoint`. Requests failing the check are
+    /// not passed to the handler; the returned failure is sent back instead.
+    fn check(&self, endpoint: E, request: &A::Request) -> Result<(), Failure<A::FailureCodeExt>>;
+}
+
+impl<E, A, F> RequestFilter<E, A> for F
+where
+    E: EndpointId,
+    A: Api,
+    F: Fn(E, &A::Request) -> Result<(), Failure<A::FailureCodeExt>>,
+{
+    fn check(&self, endpoint: E, request: &A::Request) -> Result<(), Failure<A::FailureCodeExt>> {
+        self(endpoint, request)
+    }
+}
+
 pub struct RpcServer<E, A, H>
 where
     A: Api,
@@ -63,6 +88,7 @@ where
     sessions: HashMap<E, LocalSession>,
     unmarshaller: Unmarshaller<A::Request>,
     handler: H,
+    filters: Vec<Box<dyn RequestFilter<E, A> + Send>>,
 }
 
 impl<E, A, H> RpcServer<E, A, H>
@@ -91,7 +117,15 @@ where
             });
         }
         let unmarshaller = A::Request::create_unmarshaller();
-        Ok(Self { sessions, unmarshaller, handler })
+        Ok(Self { sessions, unmarshaller, handler, filters: none!() })
+    }
+
+    /// Adds filter checking requests before they are passed to the handler.
+    /// Filters are checked in the order they were added, stopping at the first
+    /// failed one. Filters must be [`Send`], so the server can be run in a
+    /// separate thread.
+    pub fn add_filter(&mut self, filter: impl RequestFilter<E, A> + Send + 'static) {
+        self.filters.push(Box::new(filter));
     }
 }
 
@@ -161,11 +195,20 @@ where
             let raw = session.recv_raw_message()?;
             let request = &*self.unmarshaller.unmarshall(Cursor::new(raw))?;
 
+            #[cfg(feature = "tracing")]
+            let _span = debug_span!("rpc", endpoint = %endpoint).entered();
+
             debug!("RPC: got request {}", request);
-            let reply = self
-                .handler
-                .handle(endpoint, request.clone())
-                .unwrap_or_else(|err| A::Reply::from(err.into()));
+            let reply = match self.filters.iter().try_for_each(|f| f.check(endpoint, request)) {
+                Ok(()) => self
+                    .handler
+                    .handle(endpoint, request.clone())
+                    .unwrap_or_else(|err| A::Reply::from(err.into())),
+                Err(failure) => {
+                    debug!("RPC: request is rejected by the filter: {}", failure);
+                    A::Reply::from(failure)
+                }
+            };
             debug!("RPC: replying with {:?}", reply);
             let data = reply.serialize();
             session.send_raw_message(&data)?;
@@ -174,3 +217,81 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::rpc::test::{TestApi, TestEndpoint, TestFailureCode, TestReply, TestRequest};
+    use crate::rpc::FailureCode;
+
+    #[derive(Debug, Display, Error)]
+    #[display("test handler error")]
+    struct TestError;
+
+    impl crate::error::Error for TestError {}
+
+    impl From<TestError> for Failure<TestFailureCode> {
+        fn from(err: TestError) -> Self {
+            Failure { code: FailureCode::Runtime, info: err.to_string() }
+        }
+    }
+
+    /// Handler replying to all requests and recording them
+    struct Recorder {
+        handled: Arc<Mutex<Vec<TestRequest>>>,
+    }
+
+    impl Handler<TestEndpoint> for Recorder {
+        type Api = TestApi;
+        type Error = TestError;
+
+        fn handle(
+            &mut self,
+            _endpoint: TestEndpoint,
+            request: TestRequest,
+        ) -> Result<TestReply, Self::Error> {
+            let reply = request.reply();
+            self.handled.lock().unwrap().push(request);
+            Ok(reply)
+        }
+
+        fn handle_err(&mut self, error: ClientError) -> Result<(), ClientError> { Err(error) }
+    }
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    #[test]
+    fn filter_rejects_request() {
+        let addr = "inproc://rpc-filter";
+        let socket = ZMQ_CONTEXT.socket(zmq::REP).unwrap();
+        socket.bind(addr).unwrap();
+        let client = ZMQ_CONTEXT.socket(zmq::REQ).unwrap();
+        client.connect(addr).unwrap();
+        let mut client = LocalSession::with_zmq_socket(ZmqSocketType::Req, client);
+
+        let handled = Arc::new(Mutex::new(vec![]));
+        let handler = Recorder { handled: handled.clone() };
+        let endpoints = map! { TestEndpoint::Node => zeromq::Carrier::Socket(socket) };
+        let mut server = RpcServer::with(endpoints, handler).unwrap();
+        let too_large = Failure { code: FailureCode::UnexpectedRequest, info: s!("too large") };
+        let failure = too_large.clone();
+        server.add_filter(move |_endpoint, request: &TestRequest| match request {
+            TestRequest::Echo(text) if text.len() > 8 => Err(failure.clone()),
+            _ => Ok(()),
+        });
+        assert_send(&server);
+
+        let unmarshaller = TestReply::create_unmarshaller();
+        let mut replies = vec![];
+        for request in [TestRequest::Echo(s!("too large text")), TestRequest::Ping(1)] {
+            client.send_raw_message(&request.serialize()).unwrap();
+            server.run().unwrap();
+            let reply = client.recv_raw_message().unwrap();
+            replies.push((*unmarshaller.unmarshall(Cursor::new(reply)).unwrap()).clone());
+        }
+        assert_eq!(replies, vec![TestReply::Failure(too_large), TestReply::Pong(1)]);
+        assert_eq!(*handled.lock().unwrap(), vec![TestRequest::Ping(1)]);
+    }
+}
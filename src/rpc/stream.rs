@@ -0,0 +1,233 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Adapters exposing sessions as [`std::io::Read`] and [`std::io::Write`]
+//! streams for bulk data transfer, bypassing RPC request marshalling.
+//!
+//! The stream is transferred as a sequence of session messages, each carrying
+//! a chunk of data; an empty message marks the end of the stream. Since the
+//! writing side does not wait for replies, the session must not be based on
+//! ZMQ `REQ`/`REP` sockets, which require strict request-reply alternation;
+//! `PUSH`/`PULL` pair of sockets may be used instead.
+
+use std::io::{self, Read, Write};
+
+use internet2::{transport, SendRecvMessage};
+
+/// Default maximal size of data sent as a single session message
+pub const STREAM_CHUNK_SIZE: usize = 0x10000;
+
+fn io_error(err: transport::Error) -> io::Error {
+    match err {
+        transport::Error::SocketIo(kind) => kind.into(),
+        transport::Error::TimedOut => io::ErrorKind::TimedOut.into(),
+        transport::Error::ServiceOffline => io::ErrorKind::NotConnected.into(),
+        err => io::Error::new(io::ErrorKind::Other, err.to_string()),
+    }
+}
+
+/// Writes data to the session as a sequence of message chunks
+pub struct SessionWriter<'session> {
+    session: &'session mut dyn SendRecvMessage,
+    buf: Vec<u8>,
+    chunk_size: usize,
+}
+
+impl<'session> SessionWriter<'session> {
+    /// Constructs writer sending data in chunks of [`STREAM_CHUNK_SIZE`]
+    pub fn new(session: &'session mut dyn SendRecvMessage) -> Self {
+        Self::with_chunk_size(session, STREAM_CHUNK_SIZE)
+    }
+
+    /// Constructs writer sending data in chunks of `chunk_size` bytes
+    pub fn with_chunk_size(session: &'session mut dyn SendRecvMessage, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "stream chunk size must be non-zero");
+        Self { session, buf: Vec::with_capacity(chunk_size), chunk_size }
+    }
+
+    /// Sends all buffered data and marks the end of the stream. Dropping the
+    /// writer without calling this method leaves the stream unterminated.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.flush()?;
+        self.session.send_raw_message(&[]).map_err(io_error)?;
+        Ok(())
+    }
+
+    fn send_chunk(&mut self, len: usize) -> io::Result<()> {
+        self.session.send_raw_message(&self.buf[..len]).map_err(io_error)?;
+        self.buf.drain(..len);
+        Ok(())
+    }
+}
+
+impl Write for SessionWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        while self.buf.len() >= self.chunk_size {
+            self.send_chunk(self.chunk_size)?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.send_chunk(self.buf.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads data sent to the session by [`SessionWriter`] until the end of the
+/// stream
+pub struct SessionReader<'session> {
+    session: &'session mut dyn SendRecvMessage,
+    chunk: Vec<u8>,
+    pos: usize,
+    eof: bool,
+}
+
+impl<'session> SessionReader<'session> {
+    /// Constructs reader receiving stream chunks from the session
+    pub fn new(session: &'session mut dyn SendRecvMessage) -> Self {
+        Self { session, chunk: vec![], pos: 0, eof: false }
+    }
+}
+
+impl Read for SessionReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            if self.eof || buf.is_empty() {
+                return Ok(0);
+            }
+            self.chunk = self.session.recv_raw_message().map_err(io_error)?;
+            self.pos = 0;
+            if self.chunk.is_empty() {
+                self.eof = true;
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::any::Any;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use std::thread;
+
+    use internet2::session::LocalSession;
+    use internet2::{RoutedFrame, ZmqSocketType};
+
+    use super::*;
+    use crate::ZMQ_CONTEXT;
+
+    /// In-memory session sharing a queue of messages with its clones
+    #[derive(Clone, Default)]
+    struct Queue(Rc<RefCell<VecDeque<Vec<u8>>>>);
+
+    impl Queue {
+        fn lengths(&self) -> Vec<usize> { self.0.borrow().iter().map(Vec::len).collect() }
+    }
+
+    impl SendRecvMessage for Queue {
+        fn recv_raw_message(&mut self) -> Result<Vec<u8>, transport::Error> {
+            self.0.borrow_mut().pop_front().ok_or(transport::Error::TimedOut)
+        }
+
+        fn send_raw_message(&mut self, raw: &[u8]) -> Result<usize, transport::Error> {
+            self.0.borrow_mut().push_back(raw.to_vec());
+            Ok(raw.len())
+        }
+
+        fn recv_routed_message(&mut self) -> Result<RoutedFrame, transport::Error> {
+            Err(transport::Error::RequiresLocalSocket)
+        }
+
+        fn send_routed_message(
+            &mut self,
+            _source: &[u8],
+            _route: &[u8],
+            _dest: &[u8],
+            _raw: &[u8],
+        ) -> Result<usize, transport::Error> {
+            Err(transport::Error::RequiresLocalSocket)
+        }
+
+        fn into_any(self: Box<Self>) -> Box<dyn Any> { self }
+    }
+
+    fn payload(len: usize) -> Vec<u8> { (0..len).map(|i| (i % 251) as u8).collect() }
+
+    fn read_all(queue: &mut Queue) -> Vec<u8> {
+        let mut reader = SessionReader::new(queue);
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        // The stream stays at its end
+        assert_eq!(reader.read(&mut [0u8; 16]).unwrap(), 0);
+        data
+    }
+
+    #[test]
+    fn chunk_boundaries() {
+        let mut queue = Queue::default();
+        for (len, lengths) in [
+            (0, vec![0]),
+            (1000, vec![1000, 0]),
+            (2000, vec![1000, 1000, 0]),
+            (3017, vec![1000, 1000, 1000, 17, 0]),
+        ] {
+            let data = payload(len);
+            let mut sender = queue.clone();
+            let mut writer = SessionWriter::with_chunk_size(&mut sender, 1000);
+            for piece in data.chunks(777) {
+                writer.write_all(piece).unwrap();
+            }
+            // Incomplete chunk is sent only once the writer is finished
+            assert_eq!(queue.lengths(), lengths[..len / 1000]);
+            writer.finish().unwrap();
+            assert_eq!(queue.lengths(), lengths);
+            assert_eq!(read_all(&mut queue), data);
+        }
+    }
+
+    #[test]
+    fn multi_megabyte_stream() {
+        let addr = "inproc://stream";
+        let pull = ZMQ_CONTEXT.socket(zmq::PULL).unwrap();
+        pull.bind(addr).unwrap();
+        let push = ZMQ_CONTEXT.socket(zmq::PUSH).unwrap();
+        push.connect(addr).unwrap();
+
+        let data = payload(3 * 1024 * 1024 + 5);
+        let sent = data.clone();
+        let writer = thread::spawn(move || {
+            let mut session = LocalSession::with_zmq_socket(ZmqSocketType::Push, push);
+            let mut writer = SessionWriter::new(&mut session);
+            writer.write_all(&sent).unwrap();
+            writer.finish().unwrap();
+        });
+
+        let mut session = LocalSession::with_zmq_socket(ZmqSocketType::Pull, pull);
+        let mut received = vec![];
+        SessionReader::new(&mut session).read_to_end(&mut received).unwrap();
+        writer.join().unwrap();
+        assert_eq!(received.len(), data.len());
+        assert!(received == data);
+    }
+}
@@ -12,6 +12,8 @@
 // If not, see <https://opensource.org/licenses/MIT>.
 
 use std::fmt::{self, Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use amplify::Wrapper;
 
@@ -41,3 +43,44 @@ impl From<String> for OptionDetails {
 impl From<&str> for OptionDetails {
     fn from(s: &str) -> Self { OptionDetails(Some(s.to_string())) }
 }
+
+/// Source of the current time for the time-based logic (like connection
+/// backoff, request timeouts or the ESB controller watchdog), which allows to
+/// drive such logic with a mock clock in tests
+pub trait Clock {
+    /// Returns current time
+    fn now(&self) -> Instant;
+}
+
+/// Clock returning the system time
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> Instant { Instant::now() }
+}
+
+/// Manually advanced clock for deterministic tests. Clones of the clock share
+/// the same time, so the test may keep a clone to advance the clock used by
+/// the tested code.
+#[derive(Clone, Debug)]
+pub struct MockClock(Arc<Mutex<Instant>>);
+
+impl Default for MockClock {
+    fn default() -> Self { MockClock::new() }
+}
+
+impl MockClock {
+    /// Constructs clock starting at the current system time
+    pub fn new() -> Self { MockClock(Arc::new(Mutex::new(Instant::now()))) }
+
+    /// Moves the clock forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().expect("mock clock lock is poisoned") += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant { *self.0.lock().expect("mock clock lock is poisoned") }
+}